@@ -5,7 +5,7 @@ use winit::{
 use jni::{objects::{JByteArray, JObject, JValue}, JavaVM, JNIEnv};
 use anyhow::Context;
 
-use crate::{Scene, parse_scene, run};
+use crate::{Scene, parse_scene, run, parser::{ParseError, Validation}};
 
 
 
@@ -19,10 +19,17 @@ fn get_scene(env: &mut JNIEnv, activity: &JObject) -> anyhow::Result<Option<Scen
 
     let rdata = env.convert_byte_array(jdata).context("Could not convert byte array")?;
 
-    Ok(Some(parse_scene(&rdata, None).context("Cannot parse model")?))
+    let mut scene = parse_scene(&rdata, None, &Default::default()).context("Cannot parse model")?;
+    // Never upload broken scenes, indices outside of the palette would read garbage
+    let issues = scene.validate(Validation::Repair)?;
+    if !issues.is_empty() {
+        log::warn!("{}", ParseError::InvalidScene(issues));
+    }
+    Ok(Some(scene))
 }
 
 fn throw_error(env: &mut JNIEnv, activity: &JObject, error: anyhow::Error) -> anyhow::Result<()> {
+    log::error!("{:?}", error);
     let error = env.new_string(format!("{:?}", error))?;
     env.call_method(&activity, "onNativeError", "(Ljava/lang/String;)V", &[JValue::Object(&error.into())])?;
     Ok(())
@@ -51,5 +58,5 @@ fn android_main(app: AndroidApp) {
     };
 
     let event_loop = EventLoopBuilder::new().with_android_app(app).build().unwrap();
-    run(event_loop, scene);
+    run(event_loop, scene, Default::default());
 }
@@ -1,16 +1,21 @@
 use std::{borrow::Cow, mem};
 
-use cgmath::{Vector3, Point3, EuclideanSpace};
+use cgmath::{Vector3, Point3, EuclideanSpace, InnerSpace, MetricSpace};
 use wgpu::{Device, Queue, ShaderModule, TextureFormat, PipelineLayout, RenderPipeline, Instance, Adapter, util::{DeviceExt, BufferInitDescriptor}, BufferUsages};
 use winit::{event_loop::EventLoopWindowTarget, dpi::PhysicalSize};
 
-use crate::{parser::{Model, self, Scene}, camera::{CameraUniform, Camera, CameraController}, model::{ModelVertex, InstanceData}, texture::Texture};
+use crate::{parser::{Model, self, Scene}, camera::{CameraUniform, Camera, CameraController}, model::{ModelVertex, InstanceData, MeshVertex}, texture::Texture, material::Material, mesh::{greedy_faces, pack_ao, voxel_faces}, render::RenderMode};
 
 pub const CUBE_MODEL_PLY: &'static [u8] = include_bytes!("../models/pcube.ply");
+/// Side of the shadow map texture
+const SHADOW_MAP_SIZE: u32 = 2048;
+
+// The ray marcher needs a dense copy of the grid, bigger scenes are only rasterized
+const MAX_VOXEL_TEXTURE_BYTES: u64 = 512 << 20;
 
 fn load_cube() -> Model {
     let mut reader = CUBE_MODEL_PLY;
-    parser::parse_model(&mut reader)
+    parser::parse_model(&mut reader).expect("Invalid cube model")
 }
 
 pub struct RenderState {
@@ -19,7 +24,12 @@ pub struct RenderState {
     _pipeline_layout: PipelineLayout,
     pub queue: Queue,
     pub render_pipeline: RenderPipeline,
+    pub transparent_pipeline: RenderPipeline,
+    pub mesh_pipeline: RenderPipeline,
     pub depth_texture: Texture,
+    // depth seen from the light, rendered from the greedy mesh before every frame
+    pub shadow_pipeline: RenderPipeline,
+    pub shadow_texture: Texture,
 
     // model
     pub vertex_buffer: wgpu::Buffer,
@@ -28,7 +38,21 @@ pub struct RenderState {
     // instances
     pub instance_buffer: wgpu::Buffer,
     pub instance_count: u32,
+    // translucent instances, drawn after the opaque ones
+    pub transparent_instances: Vec<InstanceData>,
+    pub transparent_buffer: wgpu::Buffer,
+    pub transparent_sorted_eye: Option<Point3<f32>>,
+    // opaque voxels merged by the greedy mesher, used by `RenderMode::Greedy`
+    pub mesh_vertex_buffer: wgpu::Buffer,
+    pub mesh_index_buffer: wgpu::Buffer,
+    pub mesh_index_count: u32,
+    // the scene as a 3D texture, used by `RenderMode::Raymarch`
+    pub raymarch_pipeline: RenderPipeline,
+    pub voxel_texture: Texture,
+    pub voxel_bind_group_layout: wgpu::BindGroupLayout,
+    pub voxel_bind_group: wgpu::BindGroup,
     pub palette_texture: Texture,
+    pub material_texture: Texture,
     pub texture_bind_group_layout: wgpu::BindGroupLayout,
     pub texture_bind_group: wgpu::BindGroup,
 
@@ -43,6 +67,20 @@ pub struct RenderState {
     pub device: Device,
 }
 
+impl RenderState {
+    /// Blending needs translucent voxels to be drawn back to front,
+    /// so re-sort them whenever the camera moves
+    pub fn sort_transparent(&mut self, eye: Point3<f32>) {
+        if self.transparent_instances.is_empty() || self.transparent_sorted_eye == Some(eye) {
+            return;
+        }
+        let distance = |x: &InstanceData| Point3::from(x.pos).distance2(eye);
+        self.transparent_instances.sort_by(|a, b| distance(b).total_cmp(&distance(a)));
+        self.queue.write_buffer(&self.transparent_buffer, 0, bytemuck::cast_slice(&self.transparent_instances));
+        self.transparent_sorted_eye = Some(eye);
+    }
+}
+
 pub struct SurfaceState {
     pub window: winit::window::Window,
     pub surface: wgpu::Surface,
@@ -87,6 +125,9 @@ pub struct App {
     pub surface_state: Option<SurfaceState>,
     pub render_state: Option<RenderState>,
     pub world_state: WorldState,
+    pub render_mode: RenderMode,
+    /// What's behind the scene, offscreen images clear to transparent so they can be composited
+    pub clear_color: wgpu::Color,
 }
 
 impl App {
@@ -100,7 +141,9 @@ impl App {
                 camera: Camera::new(1.0),
                 camera_controller: CameraController::new(0.2),
                 scene: None,
-            }
+            },
+            render_mode: RenderMode::default(),
+            clear_color: wgpu::Color::BLACK,
         }
     }
 
@@ -118,7 +161,7 @@ impl App {
         self.surface_state = Some(SurfaceState { window, surface });
     }
 
-    async fn init_render_state(adapter: &Adapter, target_format: TextureFormat, window_size: PhysicalSize<u32>) -> RenderState {
+    pub async fn init_render_state(adapter: &Adapter, target_format: TextureFormat, window_size: PhysicalSize<u32>) -> RenderState {
         log::info!("Initializing render state");
 
         log::info!("WGPU: requesting device");
@@ -157,7 +200,8 @@ impl App {
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 entries: &[wgpu::BindGroupLayoutEntry {
                     binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX,
+                    // The ray marcher needs the camera in the fragment shader
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
                     ty: wgpu::BindingType::Buffer {
                         ty: wgpu::BufferBindingType::Uniform,
                         has_dynamic_offset: false,
@@ -211,7 +255,7 @@ impl App {
 
         let depth_texture =
             Texture::create_depth_texture(&device, (window_size.width, window_size.height), "depth_texture");
-
+        let shadow_texture = Texture::create_depth_texture(&device, (SHADOW_MAP_SIZE, SHADOW_MAP_SIZE), "shadow_texture");
 
         let texture_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -226,14 +270,43 @@ impl App {
                         },
                         count: None,
                     },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        },
+                        count: None,
+                    },
                     // NO SAMPLER!
                     // We will only use loadTexture (not sampleTexture)
                     // so we save space (and hopefully performance)
+                    // ...except for the shadow map, depth comparisons need one
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Depth,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                        count: None,
+                    },
                 ],
                 label: Some("texture_bind_group_layout"),
             });
 
         let palette_texture = Texture::white(&device, &queue);
+        let default_material = [Material::default().as_texel(), Material::default().as_optics_texel()].concat();
+        let material_texture = Texture::from_data(&device, &queue, &default_material, (1, 2), Some("default_material_texture"));
         let texture_bind_group = device.create_bind_group(
             &wgpu::BindGroupDescriptor {
                 layout: &texture_bind_group_layout,
@@ -242,11 +315,47 @@ impl App {
                         binding: 0,
                         resource: wgpu::BindingResource::TextureView(&palette_texture.view),
                     },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(&material_texture.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::TextureView(&shadow_texture.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: wgpu::BindingResource::Sampler(&shadow_texture.sampler),
+                    },
                 ],
                 label: Some("diffuse_bind_group"),
             }
         );
 
+        let voxel_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D3,
+                        sample_type: wgpu::TextureSampleType::Uint,
+                    },
+                    count: None,
+                }],
+                label: Some("voxel_bind_group_layout"),
+            });
+        let voxel_texture = Self::empty_voxel_texture(&device, &queue);
+        let voxel_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &voxel_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&voxel_texture.view),
+            }],
+            label: Some("voxel_bind_group"),
+        });
+
         log::info!("WGPU: creating pipeline layout");
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("pipeline_layout"),
@@ -258,26 +367,126 @@ impl App {
             push_constant_ranges: &[],
         });
 
-        log::info!("WGPU: creating render pipeline");
-        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("render_pipeline"),
+        log::info!("WGPU: creating render pipelines");
+        let create_pipeline = |label, entry_point, buffers: &[wgpu::VertexBufferLayout], blend, depth_write_enabled, cull_mode| device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(label),
             layout: Some(&pipeline_layout),
             vertex: wgpu::VertexState {
                 module: &shader,
-                entry_point: "vs_main",
-                buffers: &[ModelVertex::desc(), InstanceData::desc()],
+                entry_point,
+                buffers,
             },
             fragment: Some(wgpu::FragmentState {
                 module: &shader,
                 entry_point: "fs_main",
-                targets: &[Some(target_format.into())],
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: target_format,
+                    blend,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState {
+                cull_mode,
+                ..Default::default()
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
             }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+        let instanced = [ModelVertex::desc(), InstanceData::desc()];
+        let render_pipeline = create_pipeline("render_pipeline", "vs_main", &instanced, None, true, None);
+        // Translucent voxels are sorted back to front, they must not hide what's behind them
+        // (back faces are culled, otherwise they could be blended over the front ones)
+        let transparent_pipeline = create_pipeline(
+            "transparent_pipeline",
+            "vs_main",
+            &instanced,
+            Some(wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING),
+            false,
+            Some(wgpu::Face::Back),
+        );
+        let mesh_pipeline = create_pipeline(
+            "mesh_pipeline",
+            "vs_mesh",
+            &[MeshVertex::desc()],
+            None,
+            true,
+            None,
+        );
+
+        // Only the camera is bound, the shadow map can't be sampled while it's being rendered
+        let shadow_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("shadow_pipeline_layout"),
+            bind_group_layouts: &[&camera_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let shadow_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("shadow_pipeline"),
+            layout: Some(&shadow_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_shadow",
+                buffers: &[MeshVertex::desc()],
+            },
+            fragment: None,
             primitive: wgpu::PrimitiveState::default(),
             depth_stencil: Some(wgpu::DepthStencilState {
                 format: Texture::DEPTH_FORMAT,
                 depth_write_enabled: true,
                 depth_compare: wgpu::CompareFunction::Less,
                 stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState {
+                    constant: 2,
+                    slope_scale: 2.0,
+                    clamp: 0.0,
+                },
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        let raymarch_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("raymarch_pipeline_layout"),
+            bind_group_layouts: &[
+                &camera_bind_group_layout,
+                &texture_bind_group_layout,
+                &pos_info_bind_group_layout,
+                &voxel_bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+        // A fullscreen triangle, every pixel marches through the voxel texture
+        let raymarch_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("raymarch_pipeline"),
+            layout: Some(&raymarch_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_fullscreen",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_raymarch",
+                // Rays that miss or only go through glass let the clear color show through
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: target_format,
+                    blend: Some(wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            // The render pass has a depth attachment, but there's nothing to test against
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::Always,
+                stencil: wgpu::StencilState::default(),
                 bias: wgpu::DepthBiasState::default(),
             }),
             multisample: wgpu::MultisampleState::default(),
@@ -304,6 +513,24 @@ impl App {
             usage: wgpu::BufferUsages::VERTEX,
             mapped_at_creation: false,
         });
+        let transparent_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Transparent Instance Buffer"),
+            size: 0,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mesh_vertex_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Mesh Vertex Buffer"),
+            size: 0,
+            usage: wgpu::BufferUsages::VERTEX,
+            mapped_at_creation: false,
+        });
+        let mesh_index_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Mesh Index Buffer"),
+            size: 0,
+            usage: wgpu::BufferUsages::INDEX,
+            mapped_at_creation: false,
+        });
 
         RenderState {
             device,
@@ -312,7 +539,11 @@ impl App {
             target_format,
             _pipeline_layout: pipeline_layout,
             render_pipeline,
+            transparent_pipeline,
+            mesh_pipeline,
             depth_texture,
+            shadow_pipeline,
+            shadow_texture,
 
             camera_uniform,
             camera_buffer,
@@ -327,7 +558,18 @@ impl App {
             index_buffer,
             instance_buffer,
             instance_count: 0,
+            transparent_instances: Vec::new(),
+            transparent_buffer,
+            transparent_sorted_eye: None,
+            mesh_vertex_buffer,
+            mesh_index_buffer,
+            mesh_index_count: 0,
+            raymarch_pipeline,
+            voxel_texture,
+            voxel_bind_group_layout,
+            voxel_bind_group,
             palette_texture,
+            material_texture,
             texture_bind_group_layout,
             texture_bind_group,
         }
@@ -404,6 +646,16 @@ impl App {
         }
     }
 
+    /// Applies the pending camera movements and uploads the new point of view
+    pub fn update_camera(&mut self) {
+        self.world_state.camera_controller.update_camera(&mut self.world_state.camera);
+        if let Some(rs) = self.render_state.as_mut() {
+            rs.camera_uniform.update_view_proj(&self.world_state.camera);
+            rs.pos_info_uniform.update(&self.world_state.camera);
+            rs.sort_transparent(self.world_state.camera.eye);
+        }
+    }
+
     pub fn queue_redraw(&self) {
         if let Some(surface_state) = &self.surface_state {
             log::trace!("Making Redraw Request");
@@ -425,9 +677,7 @@ impl App {
             _ => return,
         };
 
-        let real_dims = scene.voxels.iter().fold(Vector3::<u32>::new(0, 0, 0), |a, x| {
-            Vector3::new(a.x.max(x.pos.x), a.y.max(x.pos.y), a.z.max(x.pos.z))
-        }) + Vector3::new(1, 1, 1);
+        let real_dims = scene.voxels.bounds().map_or(Vector3::new(0, 0, 0), |(_min, max)| max) + Vector3::new(1, 1, 1);
         let center = real_dims.map(|x| x as f32 / 2.0);
 
 
@@ -435,11 +685,36 @@ impl App {
         log::info!("Dims: {:?} vs {:?}", real_dims, scene.grid_size);
 
         let (palette, palette_width) = Self::create_palette(&rs, &scene);
-
-        let instances: Vec<InstanceData> = scene.voxels.iter().map(|x| InstanceData {
-            pos: [x.pos.x as f32, x.pos.y as f32, x.pos.z as f32 ],
-            color: Self::color_index_to_coord(x.color, palette_width),
-        }).collect();
+        let materials = Self::create_materials(&rs, &scene, palette_width);
+
+        let mut instances = Vec::with_capacity(scene.voxels.len());
+        let mut transparent = Vec::new();
+        let mut hidden_faces = 0;
+        for voxel in scene.voxels.iter() {
+            // Voxels enclosed on every side can never be seen
+            let visible: Vec<_> = voxel_faces(scene, voxel).collect();
+            let faces = visible.iter().fold(0u32, |mask, face| mask | 1 << face.direction);
+            hidden_faces += 6 - faces.count_ones() as usize;
+            if faces == 0 {
+                continue;
+            }
+            let instance = InstanceData {
+                pos: [voxel.pos.x as f32, voxel.pos.y as f32, voxel.pos.z as f32 ],
+                color: Self::color_index_to_coord(voxel.color, palette_width),
+                faces,
+                ao: pack_ao(&visible),
+            };
+            if scene.is_translucent(voxel.color) {
+                transparent.push(instance);
+            } else {
+                instances.push(instance);
+            }
+        }
+        let skipped = scene.voxels.len() - instances.len() - transparent.len();
+        log::info!(
+            "Skipped {skipped} hidden voxels out of {}, {} more hidden faces are discarded by the shader",
+            scene.voxels.len(), hidden_faces - skipped * 6,
+        );
         let instance_buffer = rs.device.create_buffer_init(&BufferInitDescriptor {
             label: Some("Indices Bufer"),
             contents: bytemuck::cast_slice(instances.as_slice()),
@@ -448,6 +723,46 @@ impl App {
 
         rs.instance_buffer = instance_buffer;
         rs.instance_count = instances.len() as _;
+        rs.transparent_buffer = rs.device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Transparent Instance Buffer"),
+            contents: bytemuck::cast_slice(transparent.as_slice()),
+            usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
+        });
+        rs.transparent_instances = transparent;
+        rs.transparent_sorted_eye = None;
+
+        // Translucent voxels keep using the sorted instances, the mesh only has the opaque ones
+        let faces: Vec<_> = greedy_faces(scene).into_iter()
+            .filter(|x| !scene.is_translucent(x.color))
+            .collect();
+        let mut vertices = Vec::with_capacity(faces.len() * 4);
+        let mut indices = Vec::with_capacity(faces.len() * 6);
+        for face in faces.iter() {
+            let base = vertices.len() as u32;
+            // Face corners are on the voxel grid, the cube model is centered on the voxel
+            vertices.extend(face.corners.iter().zip(face.ao).map(|(x, ao)| MeshVertex {
+                position: x.map(|x| x as f32 - 0.5),
+                normal: face.normal(),
+                color: Self::color_index_to_coord(face.color, palette_width),
+                ao: ao as f32,
+            }));
+            indices.extend(face.triangles().map(|x| base + x));
+        }
+        rs.mesh_vertex_buffer = rs.device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Mesh Vertex Buffer"),
+            contents: bytemuck::cast_slice(vertices.as_slice()),
+            usage: BufferUsages::VERTEX,
+        });
+        rs.mesh_index_buffer = rs.device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Mesh Index Buffer"),
+            contents: bytemuck::cast_slice(indices.as_slice()),
+            usage: BufferUsages::INDEX,
+        });
+        rs.mesh_index_count = indices.len() as _;
+        log::info!(
+            "Greedy mesh: {} triangles instead of {}",
+            indices.len() / 3, instances.len() * rs.model.indices.len() / 3,
+        );
         rs.texture_bind_group = rs.device.create_bind_group(
             &wgpu::BindGroupDescriptor {
                 layout: &rs.texture_bind_group_layout,
@@ -456,12 +771,38 @@ impl App {
                         binding: 0,
                         resource: wgpu::BindingResource::TextureView(&palette.view),
                     },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(&materials.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::TextureView(&rs.shadow_texture.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: wgpu::BindingResource::Sampler(&rs.shadow_texture.sampler),
+                    },
                 ],
                 label: Some("palette_bind_group"),
             }
         );
         rs.palette_texture = palette;
-        log::warn!("Loaded scene!!: {}", instances.len());
+        rs.material_texture = materials;
+
+        // A scene that can't be ray marched must not leave the previous one on screen
+        let voxels = Self::create_voxel_texture(rs, scene, real_dims)
+            .unwrap_or_else(|| Self::empty_voxel_texture(&rs.device, &rs.queue));
+        rs.voxel_bind_group = rs.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &rs.voxel_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&voxels.view),
+            }],
+            label: Some("voxel_bind_group"),
+        });
+        rs.voxel_texture = voxels;
+        log::warn!("Loaded scene!!: {} ({} translucent)", instances.len(), rs.transparent_instances.len());
         log::warn!("Center!!: {center:?}");
         //log::warn!("Instances: {:?}", instances);
 
@@ -469,6 +810,9 @@ impl App {
         camera.target = Point3::from_vec(center - Vector3::new(0.5, 0.5, 0.5));
         camera.eye = Point3::from_vec(center + Vector3::new(center.x * -4.0, center.y * 2.0, center.z * 2.0));
         camera.light = Point3::from_vec(3.0 * center);
+        // Voxels are centered on their position, the scene spans half a voxel more on every side
+        camera.scene_center = camera.target;
+        camera.scene_radius = real_dims.map(|x| x as f32).magnitude() / 2.0;
     }
 
     fn create_palette(rs: &RenderState, scene: &Scene) -> (Texture, u32) {
@@ -490,7 +834,7 @@ impl App {
             image_data.push(color.r);
             image_data.push(color.g);
             image_data.push(color.b);
-            image_data.push(255);
+            image_data.push(color.a);
         }
         // fill the rest with white
         image_data.resize(image_size_bytes, 255);
@@ -499,6 +843,48 @@ impl App {
         (tex, edge)
     }
 
+    /// Same layout as the palette, but with the material properties of each color.
+    /// The texture is twice as tall, the bottom half has the second texel of every material
+    fn create_materials(rs: &RenderState, scene: &Scene, edge: u32) -> Texture {
+        let default = Material::default();
+        let material = |i: usize| scene.materials.get(i).unwrap_or(&default);
+        let image_data: Vec<u8> = (0..(edge * edge) as usize).flat_map(|i| material(i).as_texel())
+            .chain((0..(edge * edge) as usize).flat_map(|i| material(i).as_optics_texel()))
+            .collect();
+
+        Texture::from_data(&rs.device, &rs.queue, &image_data, (edge, 2 * edge), Some("Voxel materials"))
+    }
+
+    /// Dense copy of the scene for the ray marcher, palette index + 1 in every texel (0 is empty)
+    fn create_voxel_texture(rs: &RenderState, scene: &Scene, dims: Vector3<u32>) -> Option<Texture> {
+        let max = rs.device.limits().max_texture_dimension_3d;
+        if dims.x > max || dims.y > max || dims.z > max {
+            log::warn!("The scene is too big to be ray marched ({dims:?}, at most {max} per side)");
+            return None;
+        }
+        // Every side can be within the limit and the volume still be huge
+        let bytes = dims.x as u64 * dims.y as u64 * dims.z as u64 * 4;
+        let budget = MAX_VOXEL_TEXTURE_BYTES.min(rs.device.limits().max_buffer_size);
+        if bytes > budget {
+            log::warn!("The scene is too big to be ray marched ({} MiB, at most {} MiB)", bytes >> 20, budget >> 20);
+            return None;
+        }
+
+        let [width, height] = [dims.x as usize, dims.y as usize];
+        let mut data = vec![0u32; (bytes / 4) as usize];
+        for voxel in scene.voxels.iter() {
+            let p = voxel.pos.map(|x| x as usize);
+            data[p.x + width * (p.y + height * p.z)] = voxel.color + 1;
+        }
+        log::info!("Uploading a {}x{}x{} voxel texture ({} KiB)", dims.x, dims.y, dims.z, data.len() * 4 / 1024);
+        Some(Texture::from_voxels(&rs.device, &rs.queue, &data, (dims.x, dims.y, dims.z), Some("Voxel texture")))
+    }
+
+    /// Placeholder for scenes that can't be ray marched, a single empty voxel
+    fn empty_voxel_texture(device: &Device, queue: &Queue) -> Texture {
+        Texture::from_voxels(device, queue, &[0], (1, 1, 1), Some("empty_voxel_texture"))
+    }
+
     fn color_index_to_coord(index: u32, edge: u32) -> u32 {
         (index % edge) | ((index / edge) << 16)
     }
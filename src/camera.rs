@@ -7,14 +7,26 @@ use cgmath::{prelude::*, Matrix4, Vector2};
 const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = Matrix4::new(
     1.0, 0.0, 0.0, 0.0,
     0.0, 1.0, 0.0, 0.0,
-    0.0, 0.0, 0.5, 0.5,
-    0.0, 0.0, 0.0, 1.0,
+    0.0, 0.0, 0.5, 0.0,
+    0.0, 0.0, 0.5, 1.0,
 );
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Projection {
+    #[default]
+    Perspective,
+    /// Parallel rays, the scene at the target has the same size as with the perspective projection
+    Orthographic,
+}
+
 pub struct Camera {
     pub eye: cgmath::Point3<f32>,
     pub target: cgmath::Point3<f32>,
     pub light: cgmath::Point3<f32>,
+    /// Bounding sphere of the scene, the shadow map covers it
+    pub scene_center: cgmath::Point3<f32>,
+    pub scene_radius: f32,
+    pub projection: Projection,
     up: cgmath::Vector3<f32>,
     aspect: f32,
     fovy: f32,
@@ -28,6 +40,9 @@ impl Camera {
             eye: (0.0, 5.0, 10.0).into(),
             target: (0.0, 0.0, 0.0).into(),
             light: (0.0, 0.0, 0.0).into(),
+            scene_center: (0.0, 0.0, 0.0).into(),
+            scene_radius: 1.0,
+            projection: Projection::default(),
             up: cgmath::Vector3::unit_y(),
             aspect: aspect_ratio,
             fovy: 45.0,
@@ -38,7 +53,28 @@ impl Camera {
 
     pub fn build_view_projection_matrix(&self) -> cgmath::Matrix4<f32> {
         let view = cgmath::Matrix4::look_at_rh(self.eye, self.target, self.up);
-        let proj = cgmath::perspective(cgmath::Deg(self.fovy), self.aspect, self.znear, self.zfar);
+        let distance = self.eye.distance(self.target);
+        // Big scenes put the camera far away, zooming out must not push them past the far plane
+        let zfar = self.zfar.max(distance * 3.0);
+        let proj = match self.projection {
+            Projection::Perspective => cgmath::perspective(cgmath::Deg(self.fovy), self.aspect, self.znear, zfar),
+            Projection::Orthographic => {
+                let half_height = distance * (cgmath::Deg(self.fovy) / 2.0).tan();
+                let half_width = half_height * self.aspect;
+                cgmath::ortho(-half_width, half_width, -half_height, half_height, self.znear, zfar)
+            },
+        };
+        proj * view
+    }
+
+    /// Seen from the light towards the scene, the frustum is the cone that just contains its bounding sphere
+    pub fn build_light_view_projection_matrix(&self) -> cgmath::Matrix4<f32> {
+        let distance = self.light.distance(self.scene_center).max(1.0);
+        // A light inside the sphere can't see all of it, keep the angle below 180°
+        let half_angle = (self.scene_radius / distance).min(0.95).asin();
+        let near = (distance - self.scene_radius).max(distance * 0.01);
+        let view = cgmath::Matrix4::look_at_rh(self.light, self.scene_center, self.up);
+        let proj = cgmath::perspective(cgmath::Rad(2.0 * half_angle), 1.0, near, distance + self.scene_radius);
         proj * view
     }
 
@@ -52,17 +88,26 @@ impl Camera {
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct CameraUniform {
     view_proj: [[f32; 4]; 4],
+    // Used by the ray marcher to turn screen positions back into rays
+    inv_view_proj: [[f32; 4]; 4],
+    // Where the shadow map is rendered from
+    light_view_proj: [[f32; 4]; 4],
 }
 
 impl CameraUniform {
     pub fn new() -> Self {
         Self {
             view_proj: cgmath::Matrix4::identity().into(),
+            inv_view_proj: cgmath::Matrix4::identity().into(),
+            light_view_proj: cgmath::Matrix4::identity().into(),
         }
     }
 
     pub fn update_view_proj(&mut self, camera: &Camera) {
-        self.view_proj = (OPENGL_TO_WGPU_MATRIX * camera.build_view_projection_matrix()).into();
+        let view_proj = OPENGL_TO_WGPU_MATRIX * camera.build_view_projection_matrix();
+        self.view_proj = view_proj.into();
+        self.inv_view_proj = view_proj.invert().unwrap_or(Matrix4::identity()).into();
+        self.light_view_proj = (OPENGL_TO_WGPU_MATRIX * camera.build_light_view_projection_matrix()).into();
     }
 }
 
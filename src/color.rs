@@ -1,15 +1,24 @@
 
 
-#[derive(Clone, Copy, Debug, Default)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub struct Color {
     pub r: u8,
     pub g: u8,
     pub b: u8,
+    pub a: u8,
 }
 
 impl Color {
     pub fn new(r: u8, g: u8, b: u8) -> Self {
-        Color { r, g, b}
+        Color { r, g, b, a: 255 }
+    }
+
+    pub fn with_alpha(r: u8, g: u8, b: u8, a: u8) -> Self {
+        Color { r, g, b, a }
+    }
+
+    pub fn is_opaque(&self) -> bool {
+        self.a == 255
     }
 
     pub fn as_instance_data(&self) -> [f32; 3] {
@@ -27,7 +36,7 @@ impl From<Color> for wgpu::Color {
             r: value.r as f64 / 255.0,
             g: value.g as f64 / 255.0,
             b: value.b as f64 / 255.0,
-            a: 1.0,
+            a: value.a as f64 / 255.0,
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,115 @@
+//! Golden-image tests: every model in `models/` is rendered offscreen on a software adapter
+//! and compared with the reference images in `tests/golden/`.
+//!
+//! Run with `UPDATE_GOLDEN=1` to (re)generate the references after an intended change,
+//! failed comparisons leave the actual image and a diff in `target/golden/`.
+//! Without a software adapter they fail, unless `SNOWOXEL_SKIP_GPU_TESTS` is set.
+
+use std::{fs::{self, File}, path::Path};
+
+use anyhow::Context;
+
+use crate::{headless::{skip_gpu_tests, HeadlessRenderer, write_png}, parser::parse_scene, render::RenderMode};
+
+const WIDTH: u32 = 256;
+const HEIGHT: u32 = 192;
+/// Channel difference under which two pixels are considered equal (rasterizers round differently)
+const CHANNEL_TOLERANCE: u8 = 8;
+/// Fraction of the pixels that can differ before the images are considered different
+const MAX_DIFFERENT_PIXELS: f32 = 0.002;
+
+fn root() -> &'static Path {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+}
+
+fn read_png(path: &Path) -> anyhow::Result<((u32, u32), Vec<u8>)> {
+    let decoder = png::Decoder::new(File::open(path)?);
+    let mut reader = decoder.read_info()?;
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels)?;
+    anyhow::ensure!(
+        info.color_type == png::ColorType::Rgba && info.bit_depth == png::BitDepth::Eight,
+        "References must be 8 bit RGBA images",
+    );
+    pixels.truncate(info.buffer_size());
+    Ok(((info.width, info.height), pixels))
+}
+
+/// Differing pixels are red over a dimmed copy of the reference
+fn diff_image(expected: &[u8], actual: &[u8]) -> (usize, Vec<u8>) {
+    let mut count = 0;
+    let diff = expected.chunks_exact(4).zip(actual.chunks_exact(4)).flat_map(|(a, b)| {
+        if a.iter().zip(b).any(|(a, b)| a.abs_diff(*b) > CHANNEL_TOLERANCE) {
+            count += 1;
+            [255, 0, 0, 255]
+        } else {
+            [a[0] / 4, a[1] / 4, a[2] / 4, 255]
+        }
+    }).collect();
+    (count, diff)
+}
+
+fn check_model(path: &Path, mode: RenderMode) -> anyhow::Result<()> {
+    let name = format!("{}_{mode:?}", path.file_stem().and_then(|x| x.to_str()).context("Invalid model name")?).to_lowercase();
+    let data = fs::read(path)?;
+    let scene = parse_scene(&data, path.file_name(), &Default::default())?;
+
+    // The camera is placed by `App::load_scene` from the scene bounds, so it's the same on every run
+    let mut renderer = HeadlessRenderer::new(scene, WIDTH, HEIGHT, mode, true)?;
+    let actual = renderer.render()?;
+
+    let reference = root().join("tests/golden").join(format!("{name}.png"));
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::create_dir_all(reference.parent().unwrap())?;
+        return write_png(&reference, (WIDTH, HEIGHT), &actual);
+    }
+    let (size, expected) = read_png(&reference)
+        .with_context(|| format!("Cannot read {}, run with UPDATE_GOLDEN=1 to create it", reference.display()))?;
+
+    let (different, diff) = if size == (WIDTH, HEIGHT) {
+        diff_image(&expected, &actual)
+    } else {
+        ((WIDTH * HEIGHT) as usize, vec![255; actual.len()])
+    };
+    if different as f32 <= MAX_DIFFERENT_PIXELS * (WIDTH * HEIGHT) as f32 {
+        return Ok(());
+    }
+
+    let out = root().join("target/golden");
+    fs::create_dir_all(&out)?;
+    write_png(&out.join(format!("{name}.png")), (WIDTH, HEIGHT), &actual)?;
+    write_png(&out.join(format!("{name}.diff.png")), (WIDTH, HEIGHT), &diff)?;
+    anyhow::bail!("{different} pixels differ from the reference, see {}", out.join(format!("{name}.diff.png")).display())
+}
+
+/// Checks every model with `mode`, all of them are rendered even if some fail
+fn check_all(mode: RenderMode) {
+    if skip_gpu_tests() {
+        return;
+    }
+    let mut models: Vec<_> = fs::read_dir(root().join("models"))
+        .expect("Cannot list the models")
+        .map(|x| x.expect("Cannot list the models").path())
+        .collect();
+    models.sort();
+
+    let failures: Vec<_> = models.iter()
+        .filter_map(|path| check_model(path, mode).err().map(|e| format!("{}: {e:#}", path.display())))
+        .collect();
+    assert!(failures.is_empty(), "Golden images don't match:\n{}", failures.join("\n"));
+}
+
+#[test]
+fn golden_instanced() {
+    check_all(RenderMode::Instanced);
+}
+
+#[test]
+fn golden_greedy() {
+    check_all(RenderMode::Greedy);
+}
+
+#[test]
+fn golden_raymarch() {
+    check_all(RenderMode::Raymarch);
+}
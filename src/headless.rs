@@ -0,0 +1,220 @@
+use std::{fs::File, io::BufWriter, path::Path, sync::mpsc};
+
+use anyhow::Context;
+use cgmath::Point3;
+use wgpu::{Instance, Adapter};
+use winit::dpi::PhysicalSize;
+
+use crate::{app::App, parser::{Scene, Validation}, render::{self, RenderMode}};
+
+/// What the window surfaces usually pick, so images look the same as on screen
+const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+const BYTES_PER_PIXEL: u32 = 4;
+
+/// Renders a scene without a window, into an offscreen texture that is read back to the CPU.
+/// The background is transparent, unlike in the window where it's black
+pub struct HeadlessRenderer {
+    pub app: App,
+    width: u32,
+    height: u32,
+    target: wgpu::Texture,
+    target_view: wgpu::TextureView,
+    // Rows of a texture copy must be aligned, so they can be longer than `width` pixels
+    padded_row: u32,
+    readback: wgpu::Buffer,
+}
+
+impl HeadlessRenderer {
+    /// The scene is repaired first (see `Scene::validate`), voxels outside of the grid or without a color can't be drawn.
+    /// With `software` only CPU adapters are considered, useful when images have to be reproducible
+    pub fn new(mut scene: Scene, width: u32, height: u32, render_mode: RenderMode, software: bool) -> anyhow::Result<Self> {
+        anyhow::ensure!(width > 0 && height > 0, "Invalid image size {width}x{height}");
+        scene.validate(Validation::Repair)?;
+
+        // Build servers rarely have a GPU, `WGPU_BACKEND` can pick the backend (e.g. gl for llvmpipe)
+        let instance = Instance::new(wgpu::InstanceDescriptor {
+            backends: wgpu::util::backend_bits_from_env().unwrap_or(wgpu::Backends::all()),
+            ..Default::default()
+        });
+        let adapter = pollster::block_on(request_adapter(&instance, software))?;
+        let limit = adapter.limits().max_texture_dimension_2d;
+        anyhow::ensure!(width <= limit && height <= limit, "Image size {width}x{height} is over the adapter limit ({limit})");
+
+        let rs = pollster::block_on(App::init_render_state(&adapter, FORMAT, PhysicalSize::new(width, height)));
+        let target = rs.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("headless_target"),
+            size: wgpu::Extent3d { width, height, depth_or_array_layers: 1 },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let target_view = target.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        // Within the texture limit a row fits a u32, the whole image doesn't have to
+        let padded_row = (width * BYTES_PER_PIXEL).div_ceil(align) * align;
+        let size = padded_row as u64 * height as u64;
+        let max = rs.device.limits().max_buffer_size;
+        anyhow::ensure!(size <= max, "Image size {width}x{height} needs a {size} bytes buffer, over the device limit ({max})");
+        let readback = rs.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("headless_readback"),
+            size,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut app = App::new(instance);
+        app.adapter = Some(adapter);
+        app.render_state = Some(rs);
+        app.render_mode = render_mode;
+        app.clear_color = wgpu::Color::TRANSPARENT;
+        app.world_state.scene = Some(scene);
+        app.load_scene();
+        app.world_state.camera.update_aspect_ratio(width as f32, height as f32);
+
+        Ok(Self { app, width, height, target, target_view, padded_row, readback })
+    }
+
+    /// Points the camera at `target` from `eye`, the light stays where `App::load_scene` put it
+    pub fn look_at(&mut self, eye: Point3<f32>, target: Point3<f32>) {
+        let camera = &mut self.app.world_state.camera;
+        camera.eye = eye;
+        camera.target = target;
+    }
+
+    /// Draws a frame and returns its pixels, tightly packed RGBA rows from the top
+    pub fn render(&mut self) -> anyhow::Result<Vec<u8>> {
+        self.app.update_camera();
+        render::draw(&self.app, &self.target_view);
+
+        let rs = self.app.render_state.as_ref().expect("Render state is created with the renderer");
+        let mut encoder = rs.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("headless_readback"),
+        });
+        encoder.copy_texture_to_buffer(
+            self.target.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &self.readback,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(self.padded_row),
+                    rows_per_image: Some(self.height),
+                },
+            },
+            wgpu::Extent3d { width: self.width, height: self.height, depth_or_array_layers: 1 },
+        );
+        rs.queue.submit(Some(encoder.finish()));
+
+        let slice = self.readback.slice(..);
+        let (sender, receiver) = mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |x| {
+            let _ = sender.send(x);
+        });
+        rs.device.poll(wgpu::Maintain::Wait);
+        receiver.recv()?.context("Cannot read back the rendered image")?;
+
+        let row = (self.width * BYTES_PER_PIXEL) as usize;
+        let mut pixels = Vec::with_capacity(row * self.height as usize);
+        {
+            let data = slice.get_mapped_range();
+            for line in data.chunks_exact(self.padded_row as usize) {
+                pixels.extend_from_slice(&line[..row]);
+            }
+        }
+        self.readback.unmap();
+        Ok(pixels)
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+}
+
+async fn request_adapter(instance: &Instance, software: bool) -> anyhow::Result<Adapter> {
+    let mut options = wgpu::RequestAdapterOptions {
+        power_preference: wgpu::PowerPreference::default(),
+        force_fallback_adapter: software,
+        compatible_surface: None,
+    };
+    let adapter = match instance.request_adapter(&options).await {
+        Some(x) => x,
+        None if software => anyhow::bail!("No software wgpu adapter available"),
+        None => {
+            log::info!("WGPU: no hardware adapter, falling back to software rendering");
+            options.force_fallback_adapter = true;
+            instance.request_adapter(&options).await.context("No wgpu adapter available, not even a software one")?
+        },
+    };
+    log::info!("WGPU: rendering offscreen with {:?}", adapter.get_info());
+    Ok(adapter)
+}
+
+/// Saves tightly packed RGBA pixels (as returned by `HeadlessRenderer::render`)
+pub fn write_png(path: &Path, (width, height): (u32, u32), pixels: &[u8]) -> anyhow::Result<()> {
+    let file = File::create(path).context("Cannot create output file")?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(pixels)?;
+    Ok(())
+}
+
+/// GPU tests need an adapter (Mesa's llvmpipe is enough), `SNOWOXEL_SKIP_GPU_TESTS` skips them on machines without one
+#[cfg(test)]
+pub(crate) fn skip_gpu_tests() -> bool {
+    let skip = std::env::var_os("SNOWOXEL_SKIP_GPU_TESTS").is_some();
+    if skip {
+        eprintln!("Skipping a GPU test, SNOWOXEL_SKIP_GPU_TESTS is set");
+    }
+    skip
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::Vector3;
+
+    use crate::parser::vly_scene;
+
+    use super::*;
+
+    #[test]
+    fn renders_unvalidated_scenes() {
+        if skip_gpu_tests() {
+            return;
+        }
+        for mode in [RenderMode::Instanced, RenderMode::Greedy, RenderMode::Raymarch] {
+            // A color past the palette and a voxel outside of the grid, both repaired before drawing
+            let mut scene = vly_scene("grid_size: 1 1 1\nvoxel_num: 1\n0 0 0 0\n0 255 0 0\n");
+            scene.voxels.set(Vector3::new(2, 0, 0), u32::MAX);
+
+            let mut renderer = HeadlessRenderer::new(scene, 64, 48, mode, false).unwrap();
+            assert_eq!(renderer.size(), (64, 48));
+            let pixels = renderer.render().unwrap();
+            assert_eq!(pixels.len(), 64 * 48 * 4);
+            // The scene is in the middle, the corners are background
+            let background = &pixels[..4];
+            assert!(pixels.chunks_exact(4).any(|x| x != background), "{mode:?} drew nothing");
+        }
+    }
+
+    #[test]
+    fn background_is_transparent() {
+        if skip_gpu_tests() {
+            return;
+        }
+        for mode in [RenderMode::Instanced, RenderMode::Greedy, RenderMode::Raymarch] {
+            let scene = vly_scene("grid_size: 1 1 1\nvoxel_num: 1\n0 0 0 0\n0 255 0 0\n");
+            let mut renderer = HeadlessRenderer::new(scene, 64, 48, mode, false).unwrap();
+            let pixels = renderer.render().unwrap();
+            let pixel = |x: usize, y: usize| &pixels[(y * 64 + x) * 4..][..4];
+            for corner in [pixel(0, 0), pixel(63, 0), pixel(0, 47), pixel(63, 47)] {
+                assert_eq!(corner, [0; 4], "{mode:?}");
+            }
+            // The voxel itself is opaque
+            assert!(pixels.chunks_exact(4).any(|x| x[3] == 255), "{mode:?} drew nothing opaque");
+        }
+    }
+}
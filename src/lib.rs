@@ -3,8 +3,8 @@ use parser::Scene;
 use wgpu::Instance;
 
 use winit::{
-    event::{Event, WindowEvent, KeyEvent},
-    event_loop::EventLoop, keyboard::{NamedKey, Key},
+    event::{Event, WindowEvent, KeyEvent, ElementState},
+    event_loop::EventLoop, keyboard::{NamedKey, Key, KeyCode, PhysicalKey},
 };
 use parser::parse_scene;
 
@@ -13,14 +13,23 @@ mod android;
 
 mod parser;
 mod color;
+mod material;
 mod app;
 mod camera;
 mod render;
 mod model;
+mod mesh;
 mod texture;
+mod writer;
+#[cfg(not(target_os = "android"))]
+mod headless;
+#[cfg(not(target_os = "android"))]
+mod turntable;
+#[cfg(all(test, not(target_os = "android")))]
+mod golden;
 
 
-fn run(event_loop: EventLoop<()>, initial_scene: Option<Scene>) {
+fn run(event_loop: EventLoop<()>, initial_scene: Option<Scene>, render_mode: render::RenderMode) {
     log::info!("Running mainloop...");
 
     // doesn't need to be re-considered later
@@ -33,6 +42,7 @@ fn run(event_loop: EventLoop<()>, initial_scene: Option<Scene>) {
 
     let mut app = app::App::new(instance);
     app.world_state.scene = initial_scene;
+    app.render_mode = render_mode;
 
     // It's not recommended to use `run` on Android because it will call
     // `std::process::exit` when finished which will short-circuit any
@@ -61,12 +71,7 @@ fn run(event_loop: EventLoop<()>, initial_scene: Option<Scene>) {
                 event: WindowEvent::RedrawRequested,
                 ..
             } => {
-                app.world_state.camera_controller.update_camera(&mut app.world_state.camera);
-                if let Some(rs) = app.render_state.as_mut() {
-                    rs.camera_uniform.update_view_proj(&app.world_state.camera);
-                    rs.pos_info_uniform.update(&app.world_state.camera);
-                }
-
+                app.update_camera();
                 render::render(&mut app);
             }
             Event::WindowEvent {
@@ -74,6 +79,16 @@ fn run(event_loop: EventLoop<()>, initial_scene: Option<Scene>) {
                 WindowEvent::KeyboardInput { event: KeyEvent { logical_key: Key::Named(NamedKey::BrowserBack), ..}, ..},
                 ..
             } => event_loop.exit(),
+            Event::WindowEvent {
+                event: WindowEvent::KeyboardInput {
+                    event: KeyEvent { physical_key: PhysicalKey::Code(KeyCode::KeyM), state: ElementState::Pressed, repeat: false, .. },
+                    ..
+                },
+                ..
+            } => {
+                app.render_mode = app.render_mode.next();
+                log::info!("Render mode: {:?}", app.render_mode);
+            },
             Event::WindowEvent { event, .. } => {
                 if !app.world_state.camera_controller.process_events(&event) {
                     log::debug!("Window event {:#?}", event);
@@ -96,14 +111,120 @@ fn main() {
         .parse_default_env()
         .init();
 
-    let path = env::args().skip(1).next().expect("Must provide a model path");
+    let mut path = None;
+    let mut output = None;
+    let mut options = parser::ImportOptions::default();
+    let mut strict = false;
+    let mut render_mode = render::RenderMode::default();
+    let mut render_output = None;
+    let mut software = false;
+    let mut projection = camera::Projection::default();
+    let mut turntable = None;
+    let mut layout = turntable::Layout::default();
+    let mut size = (800, 600);
+    let mut eye = None;
+    let mut target = None;
+    // "x,y,z" for the camera options
+    let parse_point = |x: Option<String>| -> Option<cgmath::Point3<f32>> {
+        let coords = x?.split(',').map(|x| x.trim().parse().ok()).collect::<Option<Vec<f32>>>()?;
+        <[f32; 3]>::try_from(coords).ok().map(Into::into)
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(args.next().expect("Must provide an output path")),
+            "-r" | "--resolution" => {
+                options.resolution = args.next()
+                    .and_then(|x| x.parse().ok())
+                    .expect("Must provide a numeric resolution");
+            },
+            "--solid" => options.solid = true,
+            "--voxel-size" => {
+                options.voxel_size = args.next()
+                    .and_then(|x| x.parse().ok())
+                    .map(Some)
+                    .expect("Must provide a numeric voxel size");
+            },
+            "--strict" => strict = true,
+            "--greedy" => render_mode = render::RenderMode::Greedy,
+            "--raymarch" => render_mode = render::RenderMode::Raymarch,
+            "--render" => render_output = Some(args.next().expect("Must provide an image path")),
+            "--size" => {
+                size = args.next()
+                    .and_then(|x| {
+                        let (w, h) = x.split_once('x')?;
+                        Some((w.parse().ok()?, h.parse().ok()?))
+                    })
+                    .expect("Must provide a size like 800x600");
+            },
+            "--software" => software = true,
+            "--ortho" => projection = camera::Projection::Orthographic,
+            "--turntable" => {
+                turntable = args.next()
+                    .and_then(|x| x.parse().ok())
+                    .map(Some)
+                    .expect("Must provide the number of frames");
+            },
+            "--sheet" => layout = turntable::Layout::Sheet,
+            "--eye" => eye = Some(parse_point(args.next()).expect("Must provide the eye position as x,y,z")),
+            "--target" => target = Some(parse_point(args.next()).expect("Must provide the target position as x,y,z")),
+            _ => path = Some(arg),
+        }
+    }
+
+    let path = path.expect("Must provide a model path");
     let path = Path::new(&path);
     let file = fs::read(path).expect("Could not open file");
 
-    let scene = parse_scene(&file, path.file_name()).expect("Invalid model provided");
+    let mut scene = match parse_scene(&file, path.file_name(), &options) {
+        Ok(x) => x,
+        Err(e) => {
+            log::error!("Invalid model provided: {e:?}");
+            std::process::exit(1);
+        },
+    };
+    let mode = if strict { parser::Validation::Strict } else { parser::Validation::Repair };
+    match scene.validate(mode) {
+        Ok(issues) if !issues.is_empty() => log::warn!("{}", parser::ParseError::InvalidScene(issues)),
+        Ok(_) => {},
+        Err(e) => {
+            log::error!("Invalid model provided: {e}");
+            std::process::exit(1);
+        },
+    }
 
     //log::info!("{scene:?}");
 
+    if let Some(output) = output {
+        writer::write_scene(&scene, Path::new(&output)).expect("Could not write output");
+        log::info!("Scene written to {output}");
+        return;
+    }
+
+    if let Some(output) = render_output {
+        let result = headless::HeadlessRenderer::new(scene, size.0, size.1, render_mode, software).and_then(|mut renderer| {
+            let camera = &mut renderer.app.world_state.camera;
+            camera.projection = projection;
+            let (default_eye, default_target) = (camera.eye, camera.target);
+            renderer.look_at(eye.unwrap_or(default_eye), target.unwrap_or(default_target));
+            match turntable {
+                Some(frames) => turntable::render_turntable(&mut renderer, frames, layout, Path::new(&output)),
+                None => {
+                    let pixels = renderer.render()?;
+                    headless::write_png(Path::new(&output), renderer.size(), &pixels)
+                },
+            }
+        });
+        match result {
+            Ok(()) => log::info!("Scene rendered to {output}"),
+            Err(e) => {
+                log::error!("Could not render the scene: {e:?}");
+                std::process::exit(1);
+            },
+        }
+        return;
+    }
+
     let event_loop = EventLoopBuilder::new().build().expect("Failed to get event loop");
-    run(event_loop, Some(scene));
+    run(event_loop, Some(scene), render_mode);
 }
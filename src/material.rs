@@ -0,0 +1,60 @@
+
+/// The values are the ones the shader branches on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MaterialKind {
+    Diffuse = 0,
+    Metal = 1,
+    Glass = 2,
+    Emissive = 3,
+}
+
+/// Surface properties of a palette entry, loosely modeled after MagicaVoxel materials.
+/// All the values are normalized in [0, 1] except for `emission` (in [0, 4]) and `ior`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Material {
+    pub kind: MaterialKind,
+    pub roughness: f32,
+    pub metalness: f32,
+    pub emission: f32,
+    pub ior: f32,
+    pub transparency: f32,
+}
+
+impl Material {
+    pub const MAX_EMISSION: f32 = 4.0;
+    pub const MAX_IOR: f32 = 3.0;
+
+    /// Packs the material in a RGBA8 texel (roughness, metalness, emission, transparency)
+    pub fn as_texel(&self) -> [u8; 4] {
+        [
+            unorm(self.roughness),
+            unorm(self.metalness),
+            unorm(self.emission / Self::MAX_EMISSION),
+            unorm(self.transparency),
+        ]
+    }
+
+    /// Second texel of the material: kind and index of refraction (mapped from [1, 3])
+    pub fn as_optics_texel(&self) -> [u8; 4] {
+        [self.kind as u8, unorm((self.ior - 1.0) / (Self::MAX_IOR - 1.0)), 0, 255]
+    }
+}
+
+fn unorm(x: f32) -> u8 {
+    (x.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        // Chosen to match the look of the plain Blinn-Phong shader
+        Material {
+            kind: MaterialKind::Diffuse,
+            roughness: 0.5,
+            metalness: 0.0,
+            emission: 0.0,
+            ior: 1.3,
+            transparency: 0.0,
+        }
+    }
+}
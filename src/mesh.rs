@@ -0,0 +1,386 @@
+use std::collections::{BTreeMap, HashMap};
+
+use cgmath::Vector3;
+
+use crate::parser::{Scene, Voxel};
+
+/// Outward normals, `Face::direction` indexes this
+pub const NORMALS: [[f32; 3]; 6] = [
+    [1.0, 0.0, 0.0], [-1.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0], [0.0, -1.0, 0.0],
+    [0.0, 0.0, 1.0], [0.0, 0.0, -1.0],
+];
+
+// Sides of every face, chosen so that u x v = normal (corners are counter-clockwise seen from outside)
+const TANGENTS: [([u32; 3], [u32; 3]); 6] = [
+    ([0, 1, 0], [0, 0, 1]), ([0, 0, 1], [0, 1, 0]),
+    ([0, 0, 1], [1, 0, 0]), ([1, 0, 0], [0, 0, 1]),
+    ([1, 0, 0], [0, 1, 0]), ([0, 1, 0], [1, 0, 0]),
+];
+
+/// Triangles of a quad, indices are relative to its corners
+pub const QUAD_TRIANGLES: [u32; 6] = [0, 1, 2, 0, 2, 3];
+
+/// A visible side of a voxel, or a rectangle of them once merged by `greedy_faces`
+pub struct Face {
+    /// Counter-clockwise, seen from outside
+    pub corners: [[u32; 3]; 4],
+    pub direction: usize,
+    pub color: u32,
+    /// Ambient occlusion of every corner, from 0 (in a crease) to 3 (nothing around)
+    pub ao: [u8; 4],
+}
+
+impl Face {
+    pub fn normal(&self) -> [f32; 3] {
+        NORMALS[self.direction]
+    }
+
+    /// Like `QUAD_TRIANGLES`, but the diagonal goes through the darker pair of corners,
+    /// otherwise the occlusion is interpolated unevenly between the two triangles
+    pub fn triangles(&self) -> [u32; 6] {
+        let [a, b, c, d] = self.ao.map(u32::from);
+        if a + c > b + d {
+            [1, 2, 3, 1, 3, 0]
+        } else {
+            QUAD_TRIANGLES
+        }
+    }
+}
+
+/// Axes spanned by the faces pointing in `direction`, in the same order as `TANGENTS`
+fn tangent_axes(direction: usize) -> (usize, usize) {
+    let (u, v) = TANGENTS[direction];
+    let axis = |x: [u32; 3]| x.iter().position(|x| *x == 1).unwrap();
+    (axis(u), axis(v))
+}
+
+fn neighbour(pos: [u32; 3], direction: usize) -> Option<[u32; 3]> {
+    let axis = direction / 2;
+    let mut pos = pos;
+    pos[axis] = if direction % 2 == 0 {
+        pos[axis].checked_add(1)?
+    } else {
+        pos[axis].checked_sub(1)?
+    };
+    Some(pos)
+}
+
+/// Corners of the side of the voxel at `pos` towards `direction`, `None` if they don't fit in a u32
+fn face_corners(pos: [u32; 3], direction: usize) -> Option<[[u32; 3]; 4]> {
+    let axis = direction / 2;
+    let mut base = pos;
+    if direction % 2 == 0 {
+        base[axis] = base[axis].checked_add(1)?;
+    }
+    let (u, v) = TANGENTS[direction];
+    let add = |a: [u32; 3], b: [u32; 3]| Some([a[0].checked_add(b[0])?, a[1].checked_add(b[1])?, a[2].checked_add(b[2])?]);
+    Some([base, add(base, u)?, add(add(base, u)?, v)?, add(base, v)?])
+}
+
+fn is_opaque(scene: &Scene, pos: Option<[u32; 3]>) -> bool {
+    pos.and_then(|[x, y, z]| scene.voxels.get(Vector3::new(x, y, z)))
+        .map_or(false, |color| !scene.is_translucent(color))
+}
+
+/// Classic voxel ambient occlusion at the `vertex` corner of a face of the voxel at `pos`:
+/// counts the opaque voxels sharing that corner in the layer in front of the face
+fn corner_occlusion(scene: &Scene, pos: [u32; 3], direction: usize, vertex: [u32; 3]) -> u8 {
+    let Some(front) = neighbour(pos, direction) else {
+        return 3;
+    };
+    let (u, v) = tangent_axes(direction);
+    // The other cell touching the vertex along `axis`
+    let other = |axis: usize| if pos[axis] == vertex[axis] { vertex[axis].checked_sub(1) } else { Some(vertex[axis]) };
+    let moved = |axes: &[usize]| axes.iter().try_fold(front, |mut cell, axis| {
+        cell[*axis] = other(*axis)?;
+        Some(cell)
+    });
+
+    let side1 = is_opaque(scene, moved(&[u]));
+    let side2 = is_opaque(scene, moved(&[v]));
+    let corner = is_opaque(scene, moved(&[u, v]));
+    if side1 && side2 {
+        0
+    } else {
+        3 - side1 as u8 - side2 as u8 - corner as u8
+    }
+}
+
+/// Sides of a voxel that can be seen from outside, bit `i` is set if the face towards `NORMALS[i]` is visible.
+/// Faces between two voxels are hidden, unless the one in front lets the light through.
+pub fn visible_directions(scene: &Scene, voxel: Voxel) -> u32 {
+    let pos = [voxel.pos.x, voxel.pos.y, voxel.pos.z];
+    let translucent = scene.is_translucent(voxel.color);
+    (0..6).filter(|direction| {
+        match neighbour(pos, *direction).and_then(|[x, y, z]| scene.voxels.get(Vector3::new(x, y, z))) {
+            // Glass next to glass merges into a single volume
+            Some(other) => scene.is_translucent(other) && !translucent,
+            None => true,
+        }
+    }).fold(0, |mask, direction| mask | 1 << direction)
+}
+
+/// Visible sides of a single voxel, see `visible_directions`
+pub fn voxel_faces(scene: &Scene, voxel: Voxel) -> impl Iterator<Item = Face> + '_ {
+    let pos = [voxel.pos.x, voxel.pos.y, voxel.pos.z];
+    let mask = visible_directions(scene, voxel);
+    (0..6).filter(move |x| mask & 1 << x != 0).filter_map(move |direction| {
+        // Only voxels at u32::MAX, that no valid grid contains, have corners out of range
+        let corners = face_corners(pos, direction)?;
+        Some(Face {
+            corners,
+            direction,
+            color: voxel.color,
+            ao: corners.map(|x| corner_occlusion(scene, pos, direction, x)),
+        })
+    })
+}
+
+/// Occlusion of the visible sides of one voxel as stored in `InstanceData`, 2 bits per corner.
+/// Corners are numbered by which side of the voxel they're on along the two other axes,
+/// in increasing order (the shader does the same)
+pub fn pack_ao(faces: &[Face]) -> [u32; 2] {
+    let mut ao = [0u32; 2];
+    for face in faces {
+        let axis = face.direction / 2;
+        let (a1, a2) = (if axis == 0 { 1 } else { 0 }, if axis == 2 { 1 } else { 2 });
+        // Unit faces start at the voxel position along the other axes
+        let low = |a: usize| face.corners.iter().map(|x| x[a]).min().unwrap_or(0);
+        let (low1, low2) = (low(a1), low(a2));
+        for (corner, level) in face.corners.iter().zip(face.ao) {
+            let k = (corner[a1] > low1) as usize | ((corner[a2] > low2) as usize) << 1;
+            let bit = 2 * (4 * face.direction + k);
+            ao[bit / 32] |= (level as u32) << (bit % 32);
+        }
+    }
+    ao
+}
+
+/// Every face that can be seen from outside the scene
+pub fn visible_faces(scene: &Scene) -> Vec<Face> {
+    scene.voxels.iter().flat_map(|voxel| voxel_faces(scene, voxel)).collect()
+}
+
+/// Faces on a plane by their position on it: color and occlusion
+type Plane = HashMap<(u32, u32), (u32, [u8; 4])>;
+
+/// Same surface as `visible_faces`, but neighbouring faces on the same plane with the same color
+/// are merged into bigger rectangles (greedy meshing), flat walls end up as a handful of quads.
+pub fn greedy_faces(scene: &Scene) -> Vec<Face> {
+    // Faces grouped by plane, (direction, position along the normal), then by position on the plane.
+    // Only the faces are stored, planes can span the whole u32 range.
+    let mut planes: BTreeMap<(usize, u32), Plane> = BTreeMap::new();
+    for face in visible_faces(scene) {
+        let (u, v) = tangent_axes(face.direction);
+        let base = face.corners[0];
+        planes.entry((face.direction, base[face.direction / 2]))
+            .or_default()
+            .insert((base[u], base[v]), (face.color, face.ao));
+    }
+
+    let mut merged = Vec::new();
+    for ((direction, depth), mut plane) in planes {
+        let (u_axis, v_axis) = tangent_axes(direction);
+        let mut starts: Vec<(u32, u32)> = plane.keys().copied().collect();
+        starts.sort_unstable_by_key(|(u, v)| (*v, *u));
+
+        for (u, v) in starts {
+            // Already part of a bigger rectangle
+            let Some(&cell) = plane.get(&(u, v)) else {
+                continue;
+            };
+            let (color, ao) = cell;
+            // Grow along u as much as possible, then along v while whole rows match.
+            // Corners of visible faces fit in a u32, so one past the last face does too.
+            // Occlusion is interpolated between the corners, only evenly lit faces can be merged
+            let (mut w, mut h) = (1, 1);
+            if ao.iter().all(|x| *x == ao[0]) {
+                while plane.get(&(u + w, v)) == Some(&cell) {
+                    w += 1;
+                }
+                while (u..u + w).all(|x| plane.get(&(x, v + h)) == Some(&cell)) {
+                    h += 1;
+                }
+            }
+            for y in v..v + h {
+                for x in u..u + w {
+                    plane.remove(&(x, y));
+                }
+            }
+
+            let mut base = [0; 3];
+            base[direction / 2] = depth;
+            base[u_axis] = u;
+            base[v_axis] = v;
+            let along = |mut pos: [u32; 3], axis: usize, len: u32| {
+                pos[axis] += len;
+                pos
+            };
+            merged.push(Face {
+                corners: [base, along(base, u_axis, w), along(along(base, u_axis, w), v_axis, h), along(base, v_axis, h)],
+                direction,
+                color,
+                ao,
+            });
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::vly_scene;
+
+    use super::*;
+
+    #[test]
+    fn hidden_faces() {
+        let scene = vly_scene("grid_size: 2 1 1\nvoxel_num: 2\n0 0 0 0\n1 0 0 0\n0 255 0 0\n");
+        let faces = visible_faces(&scene);
+        assert_eq!(faces.len(), 10);
+        // Nothing between the two voxels
+        assert!(faces.iter().all(|x| x.direction != 0 || x.corners[0][0] == 2));
+        assert!(faces.iter().all(|x| x.direction != 1 || x.corners[0][0] == 0));
+    }
+
+    #[test]
+    fn counter_clockwise_corners() {
+        for (direction, normal) in NORMALS.iter().enumerate() {
+            let [a, b, _c, d] = face_corners([5, 5, 5], direction).unwrap().map(|x| x.map(|x| x as f32));
+            let (u, v) = ([b[0] - a[0], b[1] - a[1], b[2] - a[2]], [d[0] - a[0], d[1] - a[1], d[2] - a[2]]);
+            let cross = [u[1] * v[2] - u[2] * v[1], u[2] * v[0] - u[0] * v[2], u[0] * v[1] - u[1] * v[0]];
+            assert_eq!(cross, *normal);
+        }
+    }
+
+    #[test]
+    fn corners_at_the_limit() {
+        assert_eq!(face_corners([u32::MAX - 1, 0, 0], 0).unwrap()[0], [u32::MAX, 0, 0]);
+        assert!(face_corners([u32::MAX, 0, 0], 0).is_none());
+        assert!(face_corners([u32::MAX, 0, 0], 2).is_none());
+        assert!(face_corners([u32::MAX, 0, 0], 1).is_some());
+    }
+
+    /// Unit faces covered by `faces`: (direction, position of the first corner) -> color
+    fn unit_faces(faces: &[Face]) -> Vec<(usize, [u32; 3], u32)> {
+        let mut units = Vec::new();
+        for face in faces {
+            let (u, v) = tangent_axes(face.direction);
+            let [base, _b, end, _d] = face.corners;
+            for y in base[v]..end[v] {
+                for x in base[u]..end[u] {
+                    let mut pos = base;
+                    pos[u] = x;
+                    pos[v] = y;
+                    units.push((face.direction, pos, face.color));
+                }
+            }
+        }
+        units.sort_unstable();
+        units
+    }
+
+    #[test]
+    fn flat_wall_is_one_quad() {
+        // vly files are z up, the wall stands in the x y plane of the scene
+        let voxels: String = (0..12).map(|i| format!("{} 0 {} 0\n", i % 4, i / 4)).collect();
+        let scene = vly_scene(&format!("grid_size: 4 1 3\nvoxel_num: 12\n{voxels}0 255 0 0\n"));
+        let faces = greedy_faces(&scene);
+        // One rectangle per side of the box
+        assert_eq!(faces.len(), 6);
+        let front = faces.iter().find(|x| x.direction == 4).unwrap();
+        assert_eq!(front.corners, [[0, 0, 1], [4, 0, 1], [4, 3, 1], [0, 3, 1]]);
+    }
+
+    #[test]
+    fn same_surface_as_visible_faces() {
+        // Two colors, a hole and a step
+        let scene = vly_scene(concat!(
+            "grid_size: 4 2 2\nvoxel_num: 9\n",
+            "0 0 0 0\n1 0 0 0\n2 0 0 1\n3 0 0 1\n0 1 0 0\n2 1 0 1\n3 1 0 0\n0 0 1 0\n1 0 1 0\n",
+            "0 255 0 0\n1 0 0 255\n",
+        ));
+        let greedy = greedy_faces(&scene);
+        let visible = visible_faces(&scene);
+        assert!(greedy.len() < visible.len());
+        assert_eq!(unit_faces(&greedy), unit_faces(&visible));
+    }
+
+    #[test]
+    fn far_apart_faces() {
+        // The plane between them is never allocated
+        let scene = vly_scene("grid_size: 4000000001 1 1\nvoxel_num: 2\n0 0 0 0\n4000000000 0 0 0\n0 255 0 0\n");
+        let faces = greedy_faces(&scene);
+        assert_eq!(faces.len(), 12);
+        assert_eq!(unit_faces(&faces), unit_faces(&visible_faces(&scene)));
+    }
+
+    /// Opaque voxels at scene positions, vly files are z up
+    fn voxel_scene(positions: &[[u32; 3]]) -> Scene {
+        let voxels: String = positions.iter().map(|[x, y, z]| format!("{x} {z} {y} 0\n")).collect();
+        vly_scene(&format!("grid_size: 8 8 8\nvoxel_num: {}\n{voxels}0 255 0 0\n", positions.len()))
+    }
+
+    #[test]
+    fn occlusion_of_a_corner() {
+        // Two voxels on top of [1, 0, 1], next to the +y face of [1, 0, 1] along x and z
+        let scene = voxel_scene(&[[1, 0, 1], [0, 1, 1], [1, 1, 0]]);
+        let level = |vertex| corner_occlusion(&scene, [1, 0, 1], 2, vertex);
+        // Both sides: fully occluded, whatever the diagonal is
+        assert_eq!(level([1, 1, 1]), 0);
+        assert_eq!(level([1, 1, 2]), 2);
+        assert_eq!(level([2, 1, 1]), 2);
+        assert_eq!(level([2, 1, 2]), 3);
+
+        let diagonal = voxel_scene(&[[1, 0, 1], [0, 1, 0]]);
+        assert_eq!(corner_occlusion(&diagonal, [1, 0, 1], 2, [1, 1, 1]), 2);
+        // Nothing can be in front of a face at the edge of the grid
+        assert_eq!(corner_occlusion(&diagonal, [1, 0, 1], 3, [1, 0, 1]), 3);
+    }
+
+    #[test]
+    fn triangles_split_along_the_darker_diagonal() {
+        let face = |ao| Face { corners: face_corners([0, 0, 0], 4).unwrap(), direction: 4, color: 0, ao };
+        assert_eq!(face([0, 3, 0, 3]).triangles(), QUAD_TRIANGLES);
+        assert_eq!(face([3, 3, 3, 3]).triangles(), QUAD_TRIANGLES);
+        let flipped = face([3, 0, 3, 0]).triangles();
+        assert_eq!(flipped, [1, 2, 3, 1, 3, 0]);
+        // Both triangles still cover the quad, facing the same way
+        for triangle in flipped.chunks(3) {
+            let mut triangle = triangle.to_vec();
+            let first = triangle.iter().position(|x| *x == *triangle.iter().min().unwrap()).unwrap();
+            triangle.rotate_left(first);
+            assert!(triangle[1] < triangle[2]);
+        }
+    }
+
+    #[test]
+    fn packed_occlusion() {
+        let pos = [5, 5, 5];
+        let face = |direction, ao| Face { corners: face_corners(pos, direction).unwrap(), direction, color: 0, ao };
+        // +y: corners are numbered by their side along x (bit 0) and z (bit 1)
+        let up = face(2, [0, 1, 2, 3]);
+        assert_eq!(up.corners, [[5, 6, 5], [5, 6, 6], [6, 6, 6], [6, 6, 5]]);
+        assert_eq!(pack_ao(&[up]), [1 << (16 + 2 * 2) | 2 << (16 + 2 * 3) | 3 << (16 + 2), 0]);
+        // -z is past the first word
+        assert_eq!(pack_ao(&[face(5, [3; 4])]), [0, 0xFF << 8]);
+        let faces: Vec<_> = (0..6).map(|direction| face(direction, [3; 4])).collect();
+        assert_eq!(pack_ao(&faces), [u32::MAX, 0xFFFF]);
+    }
+
+    #[test]
+    fn greedy_keeps_uneven_occlusion() {
+        // A floor with a voxel on one end, the faces of the floor next to it are darker on one side
+        let scene = voxel_scene(&[[0, 0, 0], [1, 0, 0], [2, 0, 0], [3, 0, 0], [0, 1, 0]]);
+        let floor: Vec<_> = greedy_faces(&scene).into_iter()
+            .filter(|x| x.direction == 2 && x.corners[0][1] == 1)
+            .collect();
+        assert_eq!(floor.len(), 2);
+        assert_eq!(floor[0].corners, [[1, 1, 0], [1, 1, 1], [2, 1, 1], [2, 1, 0]]);
+        assert_eq!(floor[0].ao, [2, 2, 3, 3]);
+        assert_eq!(floor[1].corners, [[2, 1, 0], [2, 1, 1], [4, 1, 1], [4, 1, 0]]);
+        assert_eq!(floor[1].ao, [3; 4]);
+        assert_eq!(unit_faces(&greedy_faces(&scene)), unit_faces(&visible_faces(&scene)));
+    }
+}
@@ -1,75 +1,289 @@
-use std:: ffi::OsStr;
+use std::{ffi::OsStr, io::Cursor};
 
 use anyhow::Context;
 use nom::Finish;
 
 mod scene;
+mod grid;
+mod error;
 mod vly;
+pub mod vly_bin;
 mod vox;
+mod qb;
+mod nbt;
+mod schem;
 mod ply_model;
+mod quantize;
+mod voxelize;
 
 pub use ply_model::Model;
-pub use scene::Scene;
+pub use scene::{Scene, Voxel, Validation};
+pub use error::ParseError;
 
 
-enum ExpectedFormat {
-    UNKNOWN,
-    VLY,
-    VOX,
+/// Settings for formats that need to be converted to voxels (meshes and point clouds)
+#[derive(Debug, Clone, Copy)]
+pub struct ImportOptions {
+    /// Voxels along the longest side of the mesh
+    pub resolution: u32,
+    /// Fill closed meshes instead of only voxelizing their surface
+    pub solid: bool,
+    /// Size of a voxel for point clouds, in the units of the file. Overrides `resolution`
+    pub voxel_size: Option<f32>,
 }
 
-fn parse_vly(data: &[u8]) -> anyhow::Result<Scene> {
-    let data = std::str::from_utf8(data)?;
+impl Default for ImportOptions {
+    fn default() -> Self {
+        ImportOptions {
+            resolution: 64,
+            solid: false,
+            voxel_size: None,
+        }
+    }
+}
+
+fn parse_vly(data: &[u8], _options: &ImportOptions) -> anyhow::Result<Scene> {
+    if data.starts_with(vly_bin::MAGIC_BYTES) {
+        let (_rest, res) = vly_bin::parse_scene(data)
+            .map_err(|e| e.map_input(|x| format!("{:?}", &x[..x.len().min(16)])))
+            .finish()
+            .context("Invalid binary vly format")?;
+        return Ok(res);
+    }
+    let data = std::str::from_utf8(data).map_err(|e| ParseError::invalid_text(data, e))?;
 
     let (_rest, res) = vly::parse_scene(data)
-        .map_err(|e| e.to_owned())
         .finish()
-        .context("Invalid vly format")?;
+        .map_err(|e| ParseError::vly(data, e))?;
     Ok(res)
 }
 
 
-fn parse_vox(data: &[u8]) -> anyhow::Result<Scene> {
-    let (_rest, mut res) = vox::parse_scene(data)
-        .map_err(|e| e.map_input(|x| format!("{:?}", x)))
+fn parse_vox(data: &[u8], _options: &ImportOptions) -> anyhow::Result<Scene> {
+    let (_rest, res) = vox::parse_scene(data)
         .finish()
-        .context("Invalid vox format")?;
-    if res.len() == 0 {
-        Err(anyhow::anyhow!("VOX pack contains no scene!"))?;
-    }
-    res.truncate(1);
-    Ok(res.pop().unwrap())
-}
-
-pub fn parse_scene(file_content: &[u8], file_name: Option<&OsStr>) -> anyhow::Result<Scene> {
-    let format = match file_name {
-        None => ExpectedFormat::UNKNOWN,
-        Some(x) => {
-            let name = x.as_encoded_bytes();
-            let ext_pos = name.iter().enumerate().rev().find(|(_i, c)| **c == b'.').map(|(i, _c)| i);
-            let ext = match ext_pos {
-                Some(x) => name.split_at(x + 1).1,
-                None => name,
-            };
-
-            match ext {
-                b"vly" => ExpectedFormat::VLY,
-                b"vox" => ExpectedFormat::VOX,
-                _ => ExpectedFormat::UNKNOWN,
-            }
-        },
-    };
+        .map_err(|e| ParseError::vox(data, e))?;
+    Ok(res)
+}
+
+fn parse_qb(data: &[u8], _options: &ImportOptions) -> anyhow::Result<Scene> {
+    let (_rest, res) = qb::parse_scene(data)
+        .map_err(|e| e.map_input(|x| format!("{:?}", &x[..x.len().min(16)])))
+        .finish()
+        .context("Invalid qb format")?;
+    Ok(res)
+}
+
+fn parse_schem(data: &[u8], _options: &ImportOptions) -> anyhow::Result<Scene> {
+    schem::parse_scene(data).context("Invalid schem format")
+}
+
+fn parse_ply(data: &[u8], options: &ImportOptions) -> anyhow::Result<Scene> {
+    let mesh = ply_model::parse_model(Cursor::new(data)).context("Invalid ply format")?;
+    // Without faces it's a point cloud
+    if mesh.indices.is_empty() {
+        return voxelize::bin_points(&mesh, options.resolution, options.voxel_size);
+    }
+    voxelize::voxelize(&mesh, options.resolution, options.solid)
+}
+
+/// Everything needed to recognize and import a format
+struct FormatHandler {
+    name: &'static str,
+    extensions: &'static [&'static str],
+    /// Recognizes the format from the first bytes of the file, if it has a signature
+    sniff: fn(&[u8]) -> bool,
+    parse: fn(&[u8], &ImportOptions) -> anyhow::Result<Scene>,
+}
+
+fn sniff_vly(data: &[u8]) -> bool {
+    let text = data.iter().position(|c| !c.is_ascii_whitespace()).map(|i| &data[i..]).unwrap_or(&[]);
+    data.starts_with(vly_bin::MAGIC_BYTES) || text.starts_with(b"grid_size:")
+}
+
+fn sniff_vox(data: &[u8]) -> bool {
+    data.starts_with(vox::MAGIC_BYTES)
+}
+
+fn sniff_qb(data: &[u8]) -> bool {
+    // The version is the only signature (only 1.1.0.0 exists), the flags after it are all booleans
+    let Some(header) = data.get(..24) else { return false };
+    let mut flags = header[4..20].chunks(4).map(|x| u32::from_le_bytes(x.try_into().unwrap()));
+    header.starts_with(&[1, 1, 0, 0]) && flags.all(|x| x <= 1)
+}
+
+fn sniff_schem(data: &[u8]) -> bool {
+    schem::sniff(data)
+}
+
+fn sniff_ply(data: &[u8]) -> bool {
+    data.starts_with(b"ply\n") || data.starts_with(b"ply\r\n")
+}
+
+/// Every supported format, new importers only need to be added here.
+/// When detection fails they're tried in this order.
+const FORMATS: &[FormatHandler] = &[
+    FormatHandler { name: "vly", extensions: &["vly", "vlyb"], sniff: sniff_vly, parse: parse_vly },
+    FormatHandler { name: "vox", extensions: &["vox"], sniff: sniff_vox, parse: parse_vox },
+    FormatHandler { name: "qb", extensions: &["qb"], sniff: sniff_qb, parse: parse_qb },
+    FormatHandler { name: "schem", extensions: &["schem"], sniff: sniff_schem, parse: parse_schem },
+    FormatHandler { name: "ply", extensions: &["ply"], sniff: sniff_ply, parse: parse_ply },
+];
+
+fn detect_format(file_content: &[u8], file_name: Option<&OsStr>) -> Option<&'static FormatHandler> {
+    // The content is more reliable than the name
+    if let Some(format) = FORMATS.iter().find(|x| (x.sniff)(file_content)) {
+        return Some(format);
+    }
+
+    let name = file_name?.as_encoded_bytes();
+    let ext_pos = name.iter().rposition(|c| *c == b'.')?;
+    let ext = &name[ext_pos + 1..];
+    FORMATS.iter().find(|x| x.extensions.iter().any(|e| e.as_bytes().eq_ignore_ascii_case(ext)))
+}
+
+pub fn parse_scene(file_content: &[u8], file_name: Option<&OsStr>, options: &ImportOptions) -> anyhow::Result<Scene> {
+    if let Some(format) = detect_format(file_content, file_name) {
+        log::info!("Loading {} file", format.name);
+        return (format.parse)(file_content, options);
+    }
 
-    match format {
-        ExpectedFormat::VLY => parse_vly(file_content),
-        ExpectedFormat::VOX => parse_vox(file_content),
-        ExpectedFormat::UNKNOWN => {
-            Err(())
-                .or_else(|_| parse_vly(file_content))
-                .or_else(|_| parse_vox(file_content))
-                .or_else(|_| Err(anyhow::anyhow!("Cannot determine format")))
-        },
+    // Keep every failure around, when nothing matches the user needs to know why
+    let mut errors = Vec::new();
+    for format in FORMATS {
+        match (format.parse)(file_content, options) {
+            Ok(scene) => return Ok(scene),
+            Err(e) => errors.push((format.name, e)),
+        }
     }
+    Err(ParseError::UnknownFormat(errors).into())
 }
 
 pub use ply_model::parse_model;
+
+/// Parses a scene written inline in the vly format
+#[cfg(test)]
+pub(crate) fn vly_scene(text: &str) -> Scene {
+    parse_scene(text.as_bytes(), None, &Default::default()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    const VLY: &[u8] = b"grid_size: 1 1 1\nvoxel_num: 1\n0 0 0 0\n0 255 0 0\n";
+
+    fn ints(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|x| x.to_le_bytes()).collect()
+    }
+
+    /// Single red voxel, only the version differs from a real file
+    fn qb(version: [u8; 4]) -> Vec<u8> {
+        let mut data = version.to_vec();
+        // RGBA, left handed, uncompressed, no visibility mask, one 1x1x1 matrix at the origin
+        data.extend(ints(&[0, 0, 0, 0, 1]));
+        data.extend([1, b'm']);
+        data.extend(ints(&[1, 1, 1, 0, 0, 0, 0xff0000ff]));
+        data
+    }
+
+    fn vox() -> Vec<u8> {
+        let chunk = |id: &[u8], content: Vec<u8>, children: Vec<u8>| {
+            [id.to_vec(), ints(&[content.len() as u32, children.len() as u32]), content, children].concat()
+        };
+        let model = [chunk(b"SIZE", ints(&[1, 1, 1]), vec![]), chunk(b"XYZI", ints(&[1, 0x01000000]), vec![])].concat();
+        [b"VOX ".to_vec(), ints(&[150]), chunk(b"MAIN", vec![], model)].concat()
+    }
+
+    fn schem() -> Vec<u8> {
+        // Only the start of the NBT root compound, enough to be recognized
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[nbt::COMPOUND_ID, 0, 0]).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    const PLY: &[u8] = b"ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\nproperty float y\nproperty float z\n\
+        element face 1\nproperty list uchar uint vertex_indices\nend_header\n0 0 0\n1 0 0\n0 1 0\n3 0 1 2\n";
+
+    fn detected(data: &[u8], file_name: Option<&str>) -> Option<&'static str> {
+        detect_format(data, file_name.map(OsStr::new)).map(|x| x.name)
+    }
+
+    #[test]
+    fn content_wins_over_the_name() {
+        let files = [
+            ("vly", VLY.to_vec()),
+            ("vly", [b"\r\n  ".as_slice(), VLY].concat()),
+            ("vly", vly_bin::MAGIC_BYTES.to_vec()),
+            ("vox", vox()),
+            ("qb", qb([1, 1, 0, 0])),
+            ("schem", schem()),
+            ("ply", PLY.to_vec()),
+        ];
+        for (format, data) in files {
+            for name in [None, Some("scene"), Some("scene.vox"), Some("scene.qb"), Some("scene.ply")] {
+                assert_eq!(detected(&data, name), Some(format), "{format} named {name:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn misleading_names_still_load() {
+        for (data, name) in [(VLY.to_vec(), "scene.qb"), (vox(), "scene.vly"), (qb([1, 1, 0, 0]), "scene.vox"), (PLY.to_vec(), "scene.schem")] {
+            let scene = parse_scene(&data, Some(OsStr::new(name)), &Default::default()).unwrap();
+            assert_ne!(scene.voxels.len(), 0, "{name}");
+        }
+    }
+
+    #[test]
+    fn qb_needs_a_whole_header() {
+        assert!(sniff_qb(&qb([1, 1, 0, 0])));
+        // The version alone is not enough, vly binary or random data could start the same way
+        assert!(!sniff_qb(&[1, 1, 0, 0]));
+        let mut bad_flags = qb([1, 1, 0, 0]);
+        bad_flags[8] = 7;
+        assert!(!sniff_qb(&bad_flags));
+    }
+
+    #[test]
+    fn extension_when_there_is_no_signature() {
+        let data = b"no signature here";
+        assert_eq!(detected(data, Some("scene.QB")), Some("qb"));
+        assert_eq!(detected(data, Some("dir.vox/scene.vlyb")), Some("vly"));
+        assert_eq!(detected(data, Some("scene.txt")), None);
+        assert_eq!(detected(data, Some("scene")), None);
+        assert_eq!(detected(data, None), None);
+    }
+
+    #[test]
+    fn unknown_files_try_every_format() {
+        // Unreleased qb versions have no signature but the qb reader accepts them
+        let data = qb([1, 1, 2, 0]);
+        assert_eq!(detected(&data, Some("scene.dat")), None);
+        let scene = parse_scene(&data, Some(OsStr::new("scene.dat")), &Default::default()).unwrap();
+        assert_eq!(scene.voxels.len(), 1);
+        assert_eq!(scene.colors[scene.voxels.iter().next().unwrap().color as usize], crate::color::Color::new(255, 0, 0));
+    }
+
+    #[test]
+    fn ply_without_faces_is_a_point_cloud() {
+        let header = "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\nproperty float y\nproperty float z\n";
+        let vertices = "0 0 0\n4 0 0\n0 4 0\n";
+        let options = ImportOptions { resolution: 4, ..Default::default() };
+
+        // Binned: one voxel per point
+        let points = format!("{header}end_header\n{vertices}");
+        let scene = parse_scene(points.as_bytes(), None, &options).unwrap();
+        assert_eq!(scene.grid_size, cgmath::Vector3::new(4, 4, 1));
+        assert_eq!(scene.voxels.len(), 3);
+
+        // The same vertices as a triangle are voxelized, the whole surface is filled
+        let mesh = format!("{header}element face 1\nproperty list uchar uint vertex_indices\nend_header\n{vertices}3 0 1 2\n");
+        let scene = parse_scene(mesh.as_bytes(), None, &options).unwrap();
+        assert!(scene.voxels.len() > 3, "{}", scene.voxels.len());
+    }
+}
@@ -0,0 +1,166 @@
+use std::fmt;
+
+use nom::error::ErrorKind;
+
+use super::scene::SceneIssue;
+
+// Scenes can have millions of broken voxels, only show the first ones
+const MAX_SHOWN_ISSUES: usize = 10;
+
+/// Error returned when a scene cannot be loaded, it points to where the file is broken
+#[derive(Debug)]
+pub enum ParseError {
+    /// Text formats, line and column start from 1
+    Vly { line: usize, column: usize, kind: ErrorKind },
+    /// The file is not valid UTF-8 text
+    InvalidText { line: usize, column: usize },
+    /// Byte offset from the start of the file, `chunk` is the innermost chunk containing it
+    Vox { offset: usize, chunk: Option<[u8; 4]>, kind: ErrorKind },
+    /// No format could read the file, with the reason every one of them gave up
+    UnknownFormat(Vec<(&'static str, anyhow::Error)>),
+    /// Parsed correctly but the content doesn't make sense, see `Scene::validate`
+    InvalidScene(Vec<SceneIssue>),
+}
+
+/// 1-based line and column of a byte offset
+fn line_column(text: &[u8], offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.iter().rposition(|c| *c == b'\n').map(|x| x + 1).unwrap_or(0);
+    let line = before.iter().filter(|c| **c == b'\n').count() + 1;
+    (line, before.len() - line_start + 1)
+}
+
+/// Position of a slice returned by nom inside the original data
+fn offset_in(data: &[u8], rest: &[u8]) -> usize {
+    (rest.as_ptr() as usize).saturating_sub(data.as_ptr() as usize).min(data.len())
+}
+
+fn describe(kind: &ErrorKind) -> &str {
+    match kind {
+        ErrorKind::Eof | ErrorKind::Count => "unexpected end of file",
+        ErrorKind::Digit => "expected a number",
+        ErrorKind::MapRes => "number out of range",
+        ErrorKind::Tag => "unexpected token",
+        ErrorKind::Verify => "invalid value",
+        ErrorKind::NonEmpty => "unexpected content",
+        ErrorKind::TooLarge => "size too large",
+        ErrorKind::Many1 => "missing required chunk",
+        x => x.description(),
+    }
+}
+
+impl ParseError {
+    pub(super) fn vly(text: &str, e: nom::error::Error<&str>) -> Self {
+        let (line, column) = line_column(text.as_bytes(), offset_in(text.as_bytes(), e.input.as_bytes()));
+        ParseError::Vly { line, column, kind: e.code }
+    }
+
+    pub(super) fn invalid_text(data: &[u8], e: std::str::Utf8Error) -> Self {
+        let (line, column) = line_column(data, e.valid_up_to());
+        ParseError::InvalidText { line, column }
+    }
+
+    pub(super) fn vox(data: &[u8], e: nom::error::Error<&[u8]>) -> Self {
+        let offset = offset_in(data, e.input);
+        ParseError::Vox { offset, chunk: super::vox::chunk_at(data, offset), kind: e.code }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Vly { line, column, kind } => {
+                write!(f, "Invalid vly file at line {line}, column {column}: {}", describe(kind))
+            },
+            ParseError::InvalidText { line, column } => {
+                write!(f, "Invalid text at line {line}, column {column}: not UTF-8")
+            },
+            ParseError::Vox { offset, chunk, kind } => {
+                write!(f, "Invalid vox file at byte {offset}")?;
+                if let Some(chunk) = chunk {
+                    write!(f, " (chunk {})", String::from_utf8_lossy(chunk))?;
+                }
+                write!(f, ": {}", describe(kind))
+            },
+            ParseError::UnknownFormat(errors) => {
+                write!(f, "Cannot determine format")?;
+                for (format, error) in errors {
+                    write!(f, "\n  {format}: {error:#}")?;
+                }
+                Ok(())
+            },
+            ParseError::InvalidScene(issues) => {
+                write!(f, "Invalid scene, {} problems found", issues.len())?;
+                for issue in issues.iter().take(MAX_SHOWN_ISSUES) {
+                    write!(f, "\n  {issue}")?;
+                }
+                if issues.len() > MAX_SHOWN_ISSUES {
+                    write!(f, "\n  ... and {} more", issues.len() - MAX_SHOWN_ISSUES)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(data: &[u8], file_name: &str) -> ParseError {
+        let e = crate::parser::parse_scene(data, Some(file_name.as_ref()), &Default::default()).unwrap_err();
+        e.downcast().unwrap()
+    }
+
+    #[test]
+    fn lines_and_columns_start_from_one() {
+        let text = b"ab\ncd\n\nef";
+        assert_eq!(line_column(text, 0), (1, 1));
+        assert_eq!(line_column(text, 4), (2, 2));
+        assert_eq!(line_column(text, 6), (3, 1));
+        assert_eq!(line_column(text, 8), (4, 2));
+        // Past the end, after the last character
+        assert_eq!(line_column(text, 100), (4, 3));
+    }
+
+    #[test]
+    fn vly_errors_point_at_the_bad_token() {
+        let text = "grid_size: 2 2 2\nvoxel_num: 2\n0 0 0 0\n1 x 0 0\n0 255 0 0\n";
+        let e = parse_error(text.as_bytes(), "broken.vly");
+        assert!(matches!(e, ParseError::Vly { line: 4, column: 3, kind: ErrorKind::Digit }), "{e:?}");
+        assert_eq!(e.to_string(), "Invalid vly file at line 4, column 3: expected a number");
+    }
+
+    #[test]
+    fn invalid_utf8_is_located() {
+        let e = parse_error(b"grid_size: 1 1 1\nvoxel_num: \xff1\n", "broken.vly");
+        assert!(matches!(e, ParseError::InvalidText { line: 2, column: 12 }), "{e:?}");
+        assert_eq!(e.to_string(), "Invalid text at line 2, column 12: not UTF-8");
+    }
+
+    #[test]
+    fn vox_errors_name_the_chunk() {
+        let e = ParseError::Vox { offset: 63, chunk: Some(*b"XYZI"), kind: ErrorKind::Eof };
+        assert_eq!(e.to_string(), "Invalid vox file at byte 63 (chunk XYZI): unexpected end of file");
+        let e = ParseError::Vox { offset: 4, chunk: None, kind: ErrorKind::Verify };
+        assert_eq!(e.to_string(), "Invalid vox file at byte 4: invalid value");
+    }
+
+    #[test]
+    fn unknown_format_lists_every_failure() {
+        let e = parse_error(b"definitely not a scene", "scene.bin");
+        let ParseError::UnknownFormat(errors) = &e else { panic!("{e:?}") };
+        let formats: Vec<_> = errors.iter().map(|(format, _)| *format).collect();
+        assert_eq!(formats, ["vly", "vox", "qb", "schem", "ply"]);
+
+        let message = e.to_string();
+        let lines: Vec<_> = message.lines().collect();
+        assert_eq!(lines.len(), 1 + formats.len(), "{message}");
+        assert_eq!(lines[0], "Cannot determine format");
+        for (line, format) in lines[1..].iter().zip(formats) {
+            assert!(line.starts_with(&format!("  {format}: ")), "{line}");
+        }
+    }
+}
@@ -0,0 +1,353 @@
+use std::{collections::HashMap, fmt};
+
+use cgmath::Vector3;
+
+use super::scene::Voxel;
+
+/// Side of a brick, the unit in which space is allocated
+pub const BRICK_SIZE: u32 = 16;
+const BRICK_VOLUME: usize = (BRICK_SIZE * BRICK_SIZE * BRICK_SIZE) as usize;
+/// Past this many voxels a brick switches to dense storage, which then takes less space per voxel
+const SPARSE_LIMIT: usize = BRICK_VOLUME / 8;
+
+#[derive(Clone)]
+enum Brick {
+    /// Cell index and color of every voxel, sorted by index
+    Sparse(Vec<(u16, u32)>),
+    Dense {
+        // Palette index of every cell (x first), only meaningful where `occupied` is set
+        colors: Box<[u32]>,
+        // One bit per cell, any u32 is a valid color so it can't mark empty cells
+        occupied: Box<[u64]>,
+        count: u32,
+    },
+}
+
+impl Brick {
+    fn len(&self) -> usize {
+        match self {
+            Brick::Sparse(voxels) => voxels.len(),
+            Brick::Dense { count, .. } => *count as usize,
+        }
+    }
+
+    fn get(&self, index: usize) -> Option<u32> {
+        match self {
+            Brick::Sparse(voxels) => voxels.binary_search_by_key(&(index as u16), |x| x.0).ok().map(|i| voxels[i].1),
+            Brick::Dense { colors, occupied, .. } => is_set(occupied, index).then(|| colors[index]),
+        }
+    }
+
+    fn set(&mut self, index: usize, color: u32) -> Option<u32> {
+        match self {
+            Brick::Sparse(voxels) => match voxels.binary_search_by_key(&(index as u16), |x| x.0) {
+                Ok(i) => Some(std::mem::replace(&mut voxels[i].1, color)),
+                Err(i) if voxels.len() < SPARSE_LIMIT => {
+                    voxels.insert(i, (index as u16, color));
+                    None
+                },
+                Err(_) => {
+                    self.make_dense();
+                    self.set(index, color)
+                },
+            },
+            Brick::Dense { colors, occupied, count } => {
+                let old = std::mem::replace(&mut colors[index], color);
+                if is_set(occupied, index) {
+                    return Some(old);
+                }
+                occupied[index / 64] |= 1 << (index % 64);
+                *count += 1;
+                None
+            },
+        }
+    }
+
+    fn remove(&mut self, index: usize) -> Option<u32> {
+        match self {
+            Brick::Sparse(voxels) => {
+                let i = voxels.binary_search_by_key(&(index as u16), |x| x.0).ok()?;
+                Some(voxels.remove(i).1)
+            },
+            Brick::Dense { colors, occupied, count } => {
+                if !is_set(occupied, index) {
+                    return None;
+                }
+                occupied[index / 64] &= !(1 << (index % 64));
+                *count -= 1;
+                Some(colors[index])
+            },
+        }
+    }
+
+    fn make_dense(&mut self) {
+        let mut colors = vec![0; BRICK_VOLUME].into_boxed_slice();
+        let mut occupied = vec![0u64; BRICK_VOLUME / 64].into_boxed_slice();
+        let voxels = match self {
+            Brick::Sparse(voxels) => std::mem::take(voxels),
+            Brick::Dense { .. } => return,
+        };
+        for (index, color) in voxels.iter() {
+            let index = *index as usize;
+            colors[index] = *color;
+            occupied[index / 64] |= 1 << (index % 64);
+        }
+        *self = Brick::Dense { colors, occupied, count: voxels.len() as u32 };
+    }
+
+    /// Voxels sorted by cell index
+    fn voxels(&self, key: [u32; 3]) -> impl Iterator<Item = Voxel> + '_ {
+        let (sparse, dense) = match self {
+            Brick::Sparse(voxels) => (Some(voxels.iter().map(|(i, c)| (*i as usize, *c))), None),
+            Brick::Dense { colors, occupied, .. } => {
+                (None, Some(colors.iter().enumerate().filter(|(i, _c)| is_set(occupied, *i)).map(|(i, c)| (i, *c))))
+            },
+        };
+        sparse.into_iter().flatten().chain(dense.into_iter().flatten())
+            .map(move |(i, color)| Voxel { pos: join(key, i), color })
+    }
+
+    #[cfg(test)]
+    fn heap_bytes(&self) -> usize {
+        match self {
+            Brick::Sparse(voxels) => voxels.capacity() * std::mem::size_of::<(u16, u32)>(),
+            Brick::Dense { colors, occupied, .. } => colors.len() * 4 + occupied.len() * 8,
+        }
+    }
+}
+
+fn is_set(occupied: &[u64], index: usize) -> bool {
+    occupied[index / 64] & (1 << (index % 64)) != 0
+}
+
+fn split(pos: Vector3<u32>) -> ([u32; 3], usize) {
+    let brick = [pos.x / BRICK_SIZE, pos.y / BRICK_SIZE, pos.z / BRICK_SIZE];
+    let (x, y, z) = (pos.x % BRICK_SIZE, pos.y % BRICK_SIZE, pos.z % BRICK_SIZE);
+    (brick, (x + BRICK_SIZE * (y + BRICK_SIZE * z)) as usize)
+}
+
+fn join(brick: [u32; 3], index: usize) -> Vector3<u32> {
+    let index = index as u32;
+    Vector3::new(
+        brick[0] * BRICK_SIZE + index % BRICK_SIZE,
+        brick[1] * BRICK_SIZE + (index / BRICK_SIZE) % BRICK_SIZE,
+        brick[2] * BRICK_SIZE + index / (BRICK_SIZE * BRICK_SIZE),
+    )
+}
+
+/// Sparse voxel storage: space is split in 16³ bricks and only the ones with voxels are allocated.
+/// Every position holds at most one voxel.
+#[derive(Clone, Default)]
+pub struct VoxelGrid {
+    bricks: HashMap<[u32; 3], Brick>,
+    len: usize,
+}
+
+impl VoxelGrid {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Palette index of the voxel at `pos`, if any
+    pub fn get(&self, pos: Vector3<u32>) -> Option<u32> {
+        let (brick, index) = split(pos);
+        self.bricks.get(&brick)?.get(index)
+    }
+
+    /// Places a voxel, returns the color of the one it replaced
+    pub fn set(&mut self, pos: Vector3<u32>, color: u32) -> Option<u32> {
+        let (brick, index) = split(pos);
+        let old = self.bricks.entry(brick).or_insert_with(|| Brick::Sparse(Vec::new())).set(index, color);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    /// Removes the voxel at `pos`, returns its color
+    pub fn remove(&mut self, pos: Vector3<u32>) -> Option<u32> {
+        let (key, index) = split(pos);
+        let brick = self.bricks.get_mut(&key)?;
+        let old = brick.remove(index)?;
+        self.len -= 1;
+        if brick.len() == 0 {
+            self.bricks.remove(&key);
+        }
+        Some(old)
+    }
+
+    /// Allocated bricks in a stable order (z, then y, then x)
+    fn sorted_bricks(&self) -> Vec<(&[u32; 3], &Brick)> {
+        let mut bricks: Vec<_> = self.bricks.iter().collect();
+        bricks.sort_unstable_by_key(|(k, _b)| [k[2], k[1], k[0]]);
+        bricks
+    }
+
+    /// Every voxel, brick by brick. The order doesn't depend on how the grid was filled
+    pub fn iter(&self) -> impl Iterator<Item = Voxel> + '_ {
+        self.sorted_bricks().into_iter().flat_map(|(key, brick)| brick.voxels(*key))
+    }
+
+    /// Voxels with `min <= pos < max`, in the same order as `iter`. Only the bricks overlapping the region are visited
+    #[allow(dead_code)] // Nothing reads part of a scene (yet)
+    pub fn iter_region(&self, min: Vector3<u32>, max: Vector3<u32>) -> impl Iterator<Item = Voxel> + '_ {
+        let inside = move |p: Vector3<u32>| {
+            p.x >= min.x && p.y >= min.y && p.z >= min.z && p.x < max.x && p.y < max.y && p.z < max.z
+        };
+        let brick_min = min.map(|x| x / BRICK_SIZE);
+        let brick_max = max.map(|x| x.div_ceil(BRICK_SIZE));
+        self.sorted_bricks().into_iter()
+            .filter(move |(k, _b)| (0..3).all(|i| k[i] >= brick_min[i] && k[i] < brick_max[i]))
+            .flat_map(move |(key, brick)| brick.voxels(*key).filter(move |v| inside(v.pos)))
+    }
+
+    #[cfg(test)]
+    fn heap_bytes(&self) -> usize {
+        let entries = self.bricks.capacity() * std::mem::size_of::<([u32; 3], Brick)>();
+        entries + self.bricks.values().map(Brick::heap_bytes).sum::<usize>()
+    }
+
+    /// Smallest and largest occupied position (inclusive)
+    pub fn bounds(&self) -> Option<(Vector3<u32>, Vector3<u32>)> {
+        self.iter().fold(None, |acc, v| match acc {
+            None => Some((v.pos, v.pos)),
+            Some((min, max)) => Some((min.zip(v.pos, u32::min), max.zip(v.pos, u32::max))),
+        })
+    }
+}
+
+impl Extend<Voxel> for VoxelGrid {
+    /// Later voxels replace earlier ones at the same position, like drawing over them
+    fn extend<T: IntoIterator<Item = Voxel>>(&mut self, iter: T) {
+        for voxel in iter {
+            self.set(voxel.pos, voxel.color);
+        }
+    }
+}
+
+impl FromIterator<Voxel> for VoxelGrid {
+    fn from_iter<T: IntoIterator<Item = Voxel>>(iter: T) -> Self {
+        let mut grid = VoxelGrid::new();
+        grid.extend(iter);
+        grid
+    }
+}
+
+impl PartialEq for VoxelGrid {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.iter().all(|v| other.get(v.pos) == Some(v.color))
+    }
+}
+
+impl fmt::Debug for VoxelGrid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_get_remove() {
+        let mut grid = VoxelGrid::new();
+        let pos = Vector3::new(40, 3, 70);
+        assert_eq!(grid.set(pos, 1), None);
+        assert_eq!(grid.set(pos, 2), Some(1));
+        assert_eq!(grid.get(pos), Some(2));
+        assert_eq!(grid.get(Vector3::new(41, 3, 70)), None);
+        assert_eq!(grid.len(), 1);
+
+        assert_eq!(grid.remove(pos), Some(2));
+        assert_eq!(grid.remove(pos), None);
+        assert_eq!(grid.len(), 0);
+        // Empty bricks are freed
+        assert!(grid.bricks.is_empty());
+    }
+
+    #[test]
+    fn any_color_is_valid() {
+        let grid: VoxelGrid = [0, u32::MAX].into_iter()
+            .map(|color| Voxel { pos: Vector3::new(color % 2, 0, 0), color })
+            .collect();
+        assert_eq!(grid.get(Vector3::new(0, 0, 0)), Some(0));
+        assert_eq!(grid.get(Vector3::new(1, 0, 0)), Some(u32::MAX));
+        assert_eq!(grid.iter().count(), 2);
+    }
+
+    #[test]
+    fn stable_order() {
+        let voxels = [[33, 0, 0], [0, 0, 33], [0, 0, 0], [5, 1, 0]]
+            .map(|pos| Voxel { pos: Vector3::from(pos), color: 0 });
+        let forward: VoxelGrid = voxels.into_iter().collect();
+        let backward: VoxelGrid = voxels.into_iter().rev().collect();
+        let order: Vec<[u32; 3]> = forward.iter().map(|v| v.pos.into()).collect();
+        assert_eq!(order, [[0, 0, 0], [5, 1, 0], [33, 0, 0], [0, 0, 33]]);
+        assert!(forward.iter().eq(backward.iter()));
+        assert_eq!(forward.bounds(), Some((Vector3::new(0, 0, 0), Vector3::new(33, 1, 33))));
+    }
+
+    #[test]
+    fn regions_cut_through_bricks() {
+        // Every 3rd position of a 40³ cube, so that every brick along the way has voxels
+        let grid: VoxelGrid = (0..40u32.pow(3)).step_by(3)
+            .map(|i| Voxel { pos: Vector3::new(i % 40, (i / 40) % 40, i / 1600), color: i })
+            .collect();
+        let regions = [
+            // Starts and ends in the middle of bricks, spanning three along x
+            ([5, 3, 17], [37, 15, 20]),
+            // Exactly one brick
+            ([16, 16, 16], [32, 32, 32]),
+            // Partly outside of the grid
+            ([30, 0, 0], [100, 1, 100]),
+            // Empty
+            ([10, 10, 10], [10, 20, 20]),
+        ];
+        for (min, max) in regions {
+            let (min, max) = (Vector3::from(min), Vector3::from(max));
+            let expected: Vec<_> = grid.iter()
+                .filter(|v| (0..3).all(|i| v.pos[i] >= min[i] && v.pos[i] < max[i]))
+                .collect();
+            let region: Vec<_> = grid.iter_region(min, max).collect();
+            assert_eq!(region, expected, "{min:?} {max:?}");
+        }
+        assert_eq!(grid.iter_region(Vector3::new(0, 0, 0), Vector3::new(40, 40, 40)).count(), grid.len());
+    }
+
+    #[test]
+    fn scattered_voxels_stay_small() {
+        // One voxel per brick, far apart
+        let grid: VoxelGrid = (0..10_000u32)
+            .map(|i| Voxel { pos: Vector3::new(i % 100, i / 100, i % 7) * 1000, color: i })
+            .collect();
+        assert_eq!(grid.len(), 10_000);
+        assert_eq!(grid.bricks.len(), 10_000);
+        let per_voxel = grid.heap_bytes() / grid.len();
+        assert!(per_voxel < 256, "{per_voxel} bytes per voxel");
+    }
+
+    #[test]
+    fn full_bricks_become_dense() {
+        let side = BRICK_SIZE;
+        let mut grid: VoxelGrid = (0..side.pow(3))
+            .map(|i| Voxel { pos: Vector3::new(i % side, (i / side) % side, i / (side * side)), color: i })
+            .collect();
+        assert!(matches!(grid.bricks[&[0, 0, 0]], Brick::Dense { .. }));
+        // Less than 5 bytes per voxel, a sorted list would take 8
+        let per_voxel = grid.heap_bytes() as f32 / grid.len() as f32;
+        assert!(per_voxel < 5.0, "{per_voxel} bytes per voxel");
+
+        // Still the same voxels after the switch
+        assert!(grid.iter().enumerate().all(|(i, v)| v.color == i as u32));
+        assert_eq!(grid.get(Vector3::new(3, 2, 1)), Some(3 + 2 * side + side * side));
+        for v in grid.clone().iter() {
+            assert_eq!(grid.remove(v.pos), Some(v.color));
+        }
+        assert!(grid.bricks.is_empty());
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+
+use nom::{
+    IResult,
+    bytes::complete::take,
+    number::complete::{be_i8, be_i16, be_i32, be_i64, be_f32, be_f64, be_u16, u8 as parse_u8},
+    multi::count,
+    combinator::map,
+    error::{ErrorKind, ParseError}, Err,
+};
+
+// Named Binary Tag format used by Minecraft (big endian)
+// https://minecraft.wiki/w/NBT_format
+
+const MAX_DEPTH: u32 = 512;
+pub const COMPOUND_ID: u8 = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tag {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    ByteArray(Vec<u8>),
+    String(String),
+    List(Vec<Tag>),
+    Compound(HashMap<String, Tag>),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
+}
+
+impl Tag {
+    pub fn get(&self, key: &str) -> Option<&Tag> {
+        match self {
+            Tag::Compound(x) => x.get(key),
+            _ => None,
+        }
+    }
+
+    /// Any integer tag, widened
+    pub fn as_int(&self) -> Option<i64> {
+        match *self {
+            Tag::Byte(x) => Some(x as i64),
+            Tag::Short(x) => Some(x as i64),
+            Tag::Int(x) => Some(x as i64),
+            Tag::Long(x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+fn parse_string(input: &[u8]) -> IResult<&[u8], String> {
+    let (input, len) = be_u16(input)?;
+    let (input, data) = take(len)(input)?;
+    // Java's "modified UTF-8", close enough for our needs
+    Ok((input, String::from_utf8_lossy(data).into_owned()))
+}
+
+/// Reads a length prefix, refusing the ones that can't fit in the rest of the input
+fn parse_len(elem_size: usize) -> impl Fn(&[u8]) -> IResult<&[u8], usize> {
+    move |input: &[u8]| {
+        let (rest, len) = be_i32(input)?;
+        let len = len.max(0) as usize;
+        if len.saturating_mul(elem_size) > rest.len() {
+            return Err(Err::Error(nom::error::Error::from_error_kind(input, ErrorKind::Eof)));
+        }
+        Ok((rest, len))
+    }
+}
+
+fn parse_payload(id: u8, depth: u32) -> impl Fn(&[u8]) -> IResult<&[u8], Tag> {
+    move |input: &[u8]| {
+        if depth > MAX_DEPTH {
+            return Err(Err::Error(nom::error::Error::from_error_kind(input, ErrorKind::TooLarge)));
+        }
+        match id {
+            1 => map(be_i8, Tag::Byte)(input),
+            2 => map(be_i16, Tag::Short)(input),
+            3 => map(be_i32, Tag::Int)(input),
+            4 => map(be_i64, Tag::Long)(input),
+            5 => map(be_f32, Tag::Float)(input),
+            6 => map(be_f64, Tag::Double)(input),
+            7 => {
+                let (input, len) = parse_len(1)(input)?;
+                map(take(len), |x: &[u8]| Tag::ByteArray(x.to_vec()))(input)
+            },
+            8 => map(parse_string, Tag::String)(input),
+            9 => {
+                let (input, elem_id) = parse_u8(input)?;
+                let (input, len) = parse_len(1)(input)?;
+                if elem_id == 0 {
+                    return Ok((input, Tag::List(Vec::new())));
+                }
+                map(count(parse_payload(elem_id, depth + 1), len), Tag::List)(input)
+            },
+            10 => {
+                let mut entries = HashMap::new();
+                let mut input = input;
+                loop {
+                    let (rest, elem_id) = parse_u8(input)?;
+                    if elem_id == 0 {
+                        return Ok((rest, Tag::Compound(entries)));
+                    }
+                    let (rest, name) = parse_string(rest)?;
+                    let (rest, value) = parse_payload(elem_id, depth + 1)(rest)?;
+                    entries.insert(name, value);
+                    input = rest;
+                }
+            },
+            11 => {
+                let (input, len) = parse_len(4)(input)?;
+                map(count(be_i32, len), Tag::IntArray)(input)
+            },
+            12 => {
+                let (input, len) = parse_len(8)(input)?;
+                map(count(be_i64, len), Tag::LongArray)(input)
+            },
+            _ => Err(Err::Error(nom::error::Error::from_error_kind(input, ErrorKind::Switch))),
+        }
+    }
+}
+
+/// Parses the root tag, returns its name and value
+pub fn parse_root(input: &[u8]) -> IResult<&[u8], (String, Tag)> {
+    let (input, id) = parse_u8(input)?;
+    if id != COMPOUND_ID {
+        return Err(Err::Error(nom::error::Error::from_error_kind(input, ErrorKind::Tag)));
+    }
+    let (input, name) = parse_string(input)?;
+    let (input, value) = parse_payload(id, 0)(input)?;
+    Ok((input, (name, value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn named(id: u8, name: &str, payload: &[u8]) -> Vec<u8> {
+        let mut res = vec![id];
+        res.extend((name.len() as u16).to_be_bytes());
+        res.extend(name.as_bytes());
+        res.extend(payload);
+        res
+    }
+
+    #[test]
+    fn every_tag() {
+        let list = [&[3u8][..], &2i32.to_be_bytes(), &7i32.to_be_bytes(), &(-1i32).to_be_bytes()].concat();
+        let int_array = [&2i32.to_be_bytes()[..], &1i32.to_be_bytes(), &5i32.to_be_bytes()].concat();
+        let inner = [named(2, "s", &(-2i16).to_be_bytes()), vec![0]].concat();
+        let root = [
+            named(1, "b", &[0xff]),
+            named(4, "l", &i64::MAX.to_be_bytes()),
+            named(6, "d", &0.5f64.to_be_bytes()),
+            named(7, "bytes", &[0, 0, 0, 2, 9, 8]),
+            named(8, "str", &[0, 2, b'h', b'i']),
+            named(9, "list", &list),
+            named(10, "inner", &inner),
+            named(11, "ints", &int_array),
+            vec![0],
+        ].concat();
+        let data = named(10, "root", &root);
+
+        let (rest, (name, tag)) = parse_root(&data).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(name, "root");
+        assert_eq!(tag.get("b"), Some(&Tag::Byte(-1)));
+        assert_eq!(tag.get("l").and_then(Tag::as_int), Some(i64::MAX));
+        assert_eq!(tag.get("d"), Some(&Tag::Double(0.5)));
+        assert_eq!(tag.get("bytes"), Some(&Tag::ByteArray(vec![9, 8])));
+        assert_eq!(tag.get("str"), Some(&Tag::String("hi".to_string())));
+        assert_eq!(tag.get("list"), Some(&Tag::List(vec![Tag::Int(7), Tag::Int(-1)])));
+        assert_eq!(tag.get("inner").and_then(|x| x.get("s")).and_then(Tag::as_int), Some(-2));
+        assert_eq!(tag.get("ints"), Some(&Tag::IntArray(vec![1, 5])));
+    }
+
+    #[test]
+    fn lengths_past_the_end() {
+        // Byte array and int array claiming more data than there is
+        let data = named(10, "", &[named(7, "a", &[0, 0, 0, 8, 1]), vec![0]].concat());
+        assert!(parse_root(&data).is_err());
+        let data = named(10, "", &[named(11, "a", &[0x7f, 0xff, 0xff, 0xff]), vec![0]].concat());
+        assert!(parse_root(&data).is_err());
+    }
+
+    #[test]
+    fn too_deep() {
+        // Lists of lists, deeper than MAX_DEPTH
+        let mut payload = vec![9, 0, 0, 0, 0];
+        for _ in 0..MAX_DEPTH + 1 {
+            payload = [&[9u8, 0, 0, 0, 1][..], &payload].concat();
+        }
+        let data = named(10, "", &[named(9, "a", &payload), vec![0]].concat());
+        assert!(parse_root(&data).is_err());
+    }
+
+    #[test]
+    fn root_must_be_a_compound() {
+        assert!(parse_root(&named(3, "", &[0, 0, 0, 1])).is_err());
+    }
+}
@@ -1,45 +1,111 @@
 use std::io::BufRead;
 
+use anyhow::Context;
+use cgmath::{Vector3, InnerSpace};
 use ply_rs::{ply, parser};
 
-use crate::model::ModelVertex;
-
-#[derive(Debug)]
-struct Face {
-    vertex_index: Vec<u32>,
-}
+use crate::{color::Color, model::ModelVertex};
 
 pub struct Model {
     pub vertices: Vec<ModelVertex>,
+    /// Triangle list, polygons are triangulated as fans
     pub indices: Vec<u32>,
+    /// Per-vertex colors, only present if every vertex has one
+    pub colors: Option<Vec<Color>>,
+    /// Per-vertex texture coordinates, only present if every vertex has them
+    #[allow(dead_code)] // Nothing is textured (yet)
+    pub uvs: Option<Vec<[f32; 2]>>,
 }
 
 
 // The structs need to implement the PropertyAccess trait, otherwise the parser doesn't know how to write to them.
-// Most functions have default, hence you only need to implement, what you expect to need.
+// Properties that aren't needed are ignored, so that any PLY file can be read.
+
+struct Vertex {
+    model: ModelVertex,
+    /// Which of x, y and z were read
+    has_position: [bool; 3],
+    has_normal: bool,
+    color: Option<[u8; 3]>,
+    uv: Option<[f32; 2]>,
+}
+
+struct Face {
+    vertex_index: Vec<u32>,
+}
+
+fn property_as_f32(property: &ply::Property) -> Option<f32> {
+    use ply_rs::ply::Property::*;
+    Some(match *property {
+        Char(x) => x as f32,
+        UChar(x) => x as f32,
+        Short(x) => x as f32,
+        UShort(x) => x as f32,
+        Int(x) => x as f32,
+        UInt(x) => x as f32,
+        Float(x) => x,
+        Double(x) => x as f32,
+        _ => return None,
+    })
+}
+
+fn property_as_indices(property: ply::Property) -> Option<Vec<u32>> {
+    use ply_rs::ply::Property::*;
+    Some(match property {
+        ListChar(x) => x.into_iter().map(|x| x as u32).collect(),
+        ListUChar(x) => x.into_iter().map(|x| x as u32).collect(),
+        ListShort(x) => x.into_iter().map(|x| x as u32).collect(),
+        ListUShort(x) => x.into_iter().map(|x| x as u32).collect(),
+        ListInt(x) => x.into_iter().map(|x| x as u32).collect(),
+        ListUInt(x) => x,
+        _ => return None,
+    })
+}
 
-impl ply::PropertyAccess for ModelVertex {
+impl ply::PropertyAccess for Vertex {
     fn new() -> Self {
-        ModelVertex {
-            position: Default::default(),
-            normal: Default::default(),
+        Vertex {
+            model: ModelVertex {
+                position: Default::default(),
+                normal: Default::default(),
+            },
+            has_position: [false; 3],
+            has_normal: false,
+            color: None,
+            uv: None,
         }
     }
     fn set_property(&mut self, key: String, property: ply::Property) {
-        use ply_rs::ply::Property::Float;
-        match (key.as_ref(), property) {
-            ("x", Float(v)) => self.position[0] = v,
-            ("y", Float(v)) => self.position[1] = v,
-            ("z", Float(v)) => self.position[2] = v,
-            ("nx", Float(v)) => self.normal[0] = v,
-            ("ny", Float(v)) => self.normal[1] = v,
-            ("nz", Float(v)) => self.normal[2] = v,
-            (k, _) => panic!("Vertex: Unexpected key/value combination: key: {}", k),
-        }
+        // Lists can't be coordinates or colors, a vertex without a position is rejected later
+        let Some(value) = property_as_f32(&property) else { return };
+        let channel = match key.as_ref() {
+            "x" | "y" | "z" => {
+                let axis = (key.as_bytes()[0] - b'x') as usize;
+                self.model.position[axis] = value;
+                self.has_position[axis] = true;
+                return;
+            },
+            "nx" => { self.model.normal[0] = value; self.has_normal = true; return; },
+            "ny" => { self.model.normal[1] = value; self.has_normal = true; return; },
+            "nz" => { self.model.normal[2] = value; self.has_normal = true; return; },
+            "u" | "s" | "texture_u" | "texture_s" => { self.uv.get_or_insert([0.0; 2])[0] = value; return; },
+            "v" | "t" | "texture_v" | "texture_t" => { self.uv.get_or_insert([0.0; 2])[1] = value; return; },
+            "red" | "diffuse_red" | "r" => 0,
+            "green" | "diffuse_green" | "g" => 1,
+            "blue" | "diffuse_blue" | "b" => 2,
+            // Everything else is not needed
+            _ => return,
+        };
+        let value = match property {
+            ply::Property::UChar(x) => x,
+            // Floating point colors are normalized
+            ply::Property::Float(_) | ply::Property::Double(_) => (value.clamp(0.0, 1.0) * 255.0).round() as u8,
+            _ => value.clamp(0.0, 255.0) as u8,
+        };
+        self.color.get_or_insert([0; 3])[channel] = value;
     }
 }
 
-// same thing for Face
 impl ply::PropertyAccess for Face {
     fn new() -> Self {
         Face {
@@ -47,37 +113,179 @@ impl ply::PropertyAccess for Face {
         }
     }
     fn set_property(&mut self, key: String, property: ply::Property) {
-        match (key.as_ref(), property) {
-            ("vertex_indices", ply::Property::ListUInt(vec)) => self.vertex_index = vec,
-            (k, _) => panic!("Face: Unexpected key/value combination: key: {}", k),
+        if key == "vertex_indices" || key == "vertex_index" {
+            self.vertex_index = property_as_indices(property).unwrap_or_default();
+        }
+    }
+}
+
+/// Area weighted smooth normals, for files that don't have their own
+fn compute_normals(vertices: &mut [ModelVertex], indices: &[u32]) {
+    let position = |i: u32| Vector3::from(vertices[i as usize].position);
+    let mut normals = vec![Vector3::new(0.0f32, 0.0, 0.0); vertices.len()];
+    for tri in indices.chunks_exact(3) {
+        let normal = (position(tri[1]) - position(tri[0])).cross(position(tri[2]) - position(tri[0]));
+        for i in tri {
+            normals[*i as usize] += normal;
+        }
+    }
+    for (vertex, normal) in vertices.iter_mut().zip(normals) {
+        if normal.magnitude2() > 0.0 {
+            vertex.normal = normal.normalize().into();
         }
     }
 }
 
-/// Demonstrates simplest use case for reading from a file.
-pub fn parse_model(mut f: impl BufRead) -> Model {
-    let vertex_parser = parser::Parser::<ModelVertex>::new();
+pub fn parse_model(mut f: impl BufRead) -> anyhow::Result<Model> {
+    let vertex_parser = parser::Parser::<Vertex>::new();
     let face_parser = parser::Parser::<Face>::new();
+    let other_parser = parser::Parser::<ply::DefaultElement>::new();
 
-    let header = vertex_parser.read_header(&mut f).unwrap();
+    let header = vertex_parser.read_header(&mut f).context("Invalid PLY header")?;
 
     let mut vertex_list = Vec::new();
     let mut face_list = Vec::new();
     for (_ignore_key, element) in &header.elements {
+        // Elements are stored one after the other, the unknown ones still need to be read
         match element.name.as_ref() {
-            "vertex" => { vertex_list = vertex_parser.read_payload_for_element(&mut f, &element, &header).unwrap(); },
-            "face" => { face_list = face_parser.read_payload_for_element(&mut f, &element, &header).unwrap(); },
-            _ => panic!("Enexpeced element!"),
+            "vertex" => { vertex_list = vertex_parser.read_payload_for_element(&mut f, element, &header).context("Invalid vertex")?; },
+            "face" => { face_list = face_parser.read_payload_for_element(&mut f, element, &header).context("Invalid face")?; },
+            _ => { other_parser.read_payload_for_element(&mut f, element, &header).with_context(|| format!("Invalid {}", element.name))?; },
         }
     }
-    let index_count = face_list.iter().map(|x| x.vertex_index.len()).sum();
-    let mut indices = Vec::with_capacity(index_count);
-    for face in face_list.into_iter() {
-        indices.extend(face.vertex_index.into_iter())
+
+    if vertex_list.iter().any(|x| x.has_position != [true; 3]) {
+        anyhow::bail!("Vertex positions must be numeric x, y and z");
     }
 
-    return Model {
-        vertices: vertex_list,
-        indices: indices,
+    let mut indices = Vec::with_capacity(face_list.len() * 3);
+    for face in face_list.iter() {
+        let face = &face.vertex_index;
+        if let Some(missing) = face.iter().find(|x| **x as usize >= vertex_list.len()) {
+            anyhow::bail!("Face references missing vertex {missing}");
+        }
+        for i in 2..face.len() {
+            indices.extend([face[0], face[i - 1], face[i]]);
+        }
+    }
+
+    let all_present = |f: fn(&Vertex) -> bool| !vertex_list.is_empty() && vertex_list.iter().all(f);
+    let colors = all_present(|x| x.color.is_some()).then(|| {
+        vertex_list.iter().map(|x| {
+            let [r, g, b] = x.color.unwrap();
+            Color::new(r, g, b)
+        }).collect()
+    });
+    let uvs = all_present(|x| x.uv.is_some()).then(|| vertex_list.iter().map(|x| x.uv.unwrap()).collect());
+    let has_normals = all_present(|x| x.has_normal);
+
+    let mut vertices: Vec<ModelVertex> = vertex_list.into_iter().map(|x| x.model).collect();
+    if !has_normals {
+        compute_normals(&mut vertices, &indices);
+    }
+
+    Ok(Model {
+        vertices,
+        indices,
+        colors,
+        uvs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> anyhow::Result<Model> {
+        parse_model(text.as_bytes())
+    }
+
+    fn error(text: &str) -> String {
+        match parse(text) {
+            Ok(_) => panic!("Expected an error for {text}"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    const SQUARE: &str = "0 0 0\n1 0 0\n1 1 0\n0 1 0\n";
+
+    fn header(vertex_properties: &str, index_list: &str) -> String {
+        format!("ply\nformat ascii 1.0\nelement vertex 4\n{vertex_properties}element face 1\nproperty list {index_list} vertex_indices\nend_header\n")
+    }
+
+    #[test]
+    fn any_number_types() {
+        let xyz = "property double x\nproperty double y\nproperty double z\n";
+        for list in ["uchar int", "uchar uchar", "int uint", "uchar short"] {
+            let model = parse(&(header(xyz, list) + SQUARE + "3 0 1 2\n")).unwrap();
+            assert_eq!(model.vertices[2].position, [1.0, 1.0, 0.0], "{list}");
+            assert_eq!(model.indices, [0, 1, 2], "{list}");
+        }
+    }
+
+    #[test]
+    fn polygons_become_fans() {
+        let xyz = "property float x\nproperty float y\nproperty float z\n";
+        let model = parse(&(header(xyz, "uchar int") + SQUARE + "4 0 1 2 3\n")).unwrap();
+        assert_eq!(model.indices, [0, 1, 2, 0, 2, 3]);
+        // Counter-clockwise in the xy plane, the computed normals point to +z
+        for vertex in &model.vertices {
+            assert_eq!(vertex.normal, [0.0, 0.0, 1.0]);
+        }
+        assert!(model.colors.is_none());
+        assert!(model.uvs.is_none());
+    }
+
+    #[test]
+    fn unknown_elements_are_skipped() {
+        let text = "ply\nformat ascii 1.0\nelement material 2\nproperty uchar shininess\nproperty list uchar float extra\n\
+            element vertex 3\nproperty float x\nproperty float y\nproperty float z\nproperty float confidence\n\
+            element face 1\nproperty list uchar uint vertex_index\nend_header\n\
+            3 2 0.5 1.5\n7 0\n0 0 0 1\n1 0 0 1\n0 1 0 1\n3 0 1 2\n";
+        let model = parse(text).unwrap();
+        assert_eq!(model.vertices.len(), 3);
+        assert_eq!(model.indices, [0, 1, 2]);
+    }
+
+    #[test]
+    fn colors_uvs_and_normals() {
+        let properties = "property float x\nproperty float y\nproperty float z\nproperty float nx\nproperty float ny\nproperty float nz\n\
+            property float s\nproperty float t\n";
+        let vertices = "0 0 0 1 0 0 0 0\n1 0 0 1 0 0 1 0\n1 1 0 1 0 0 1 1\n0 1 0 1 0 0 0 1\n";
+        // Float colors are normalized, uchar ones are used as they are
+        let float_colors = "property float red\nproperty float green\nproperty float blue\n";
+        let float_values = ["1 0 0.5", "0 1 0", "0 0 1", "2 -1 0"];
+        let uchar_colors = "property uchar red\nproperty uchar green\nproperty uchar blue\n";
+        let uchar_values = ["255 0 128", "0 255 0", "0 0 255", "255 0 0"];
+        for (colors, values) in [(float_colors, float_values), (uchar_colors, uchar_values)] {
+            let vertices: String = vertices.lines().zip(values).map(|(v, c)| format!("{v} {c}\n")).collect();
+            let model = parse(&(header(&(properties.to_owned() + colors), "uchar int") + &vertices + "4 0 1 2 3\n")).unwrap();
+            assert_eq!(model.colors.unwrap(), [
+                Color::new(255, 0, 128), Color::new(0, 255, 0), Color::new(0, 0, 255), Color::new(255, 0, 0),
+            ]);
+            assert_eq!(model.uvs.unwrap(), [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]]);
+            // The file normals are kept, even if they don't match the winding
+            assert_eq!(model.vertices[0].normal, [1.0, 0.0, 0.0]);
+        }
+    }
+
+    #[test]
+    fn missing_vertices_are_rejected() {
+        let xyz = "property float x\nproperty float y\nproperty float z\n";
+        for face in ["3 0 1 4\n", "3 0 1 -1\n"] {
+            let e = error(&(header(xyz, "uchar int") + SQUARE + face));
+            assert!(e.to_string().starts_with("Face references missing vertex"), "{e}");
+        }
+    }
+
+    #[test]
+    fn positions_are_required() {
+        let no_z = "property float x\nproperty float y\n";
+        let text = header(no_z, "uchar int") + "0 0\n1 0\n1 1\n0 1\n3 0 1 2\n";
+        assert_eq!(error(&text), "Vertex positions must be numeric x, y and z");
+
+        let list_z = "property float x\nproperty float y\nproperty list uchar float z\n";
+        let text = header(list_z, "uchar int") + "0 0 1 0\n1 0 1 0\n1 1 1 0\n0 1 1 0\n3 0 1 2\n";
+        assert_eq!(error(&text), "Vertex positions must be numeric x, y and z");
     }
 }
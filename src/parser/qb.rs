@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+
+use cgmath::Vector3;
+use nom::{
+    IResult,
+    bytes::complete::take,
+    number::complete::{le_u32, le_i32, u8 as parse_u8},
+    sequence::tuple,
+    multi::count,
+    error::{ErrorKind, ParseError, FromExternalError}, Err,
+};
+
+use crate::color::Color;
+
+use super::scene::{place_voxels, Voxel, Scene};
+
+// Qubicle Binary: https://getqubicle.com/qubicle/documentation/docs/file/qb/
+const CODEFLAG: u32 = 2;
+const NEXTSLICEFLAG: u32 = 6;
+
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    bgra: bool,
+    right_handed: bool,
+    compressed: bool,
+    num_matrices: u32,
+}
+
+struct Matrix {
+    size: Vector3<u32>,
+    pos: Vector3<i32>,
+    // (x, y, z) in matrix space and the raw color
+    voxels: Vec<(Vector3<u32>, u32)>,
+}
+
+fn parse_header(input: &[u8]) -> IResult<&[u8], Header> {
+    let (input, (version, color_format, z_axis, compressed, _visibility_mask, num_matrices)) =
+        tuple((le_u32, le_u32, le_u32, le_u32, le_u32, le_u32))(input)?;
+    // Only 1.1.0.0 has ever been released
+    if version.to_le_bytes()[..2] != [1, 1] || color_format > 1 || z_axis > 1 || compressed > 1 {
+        return Err(Err::Error(nom::error::Error::from_external_error(input, ErrorKind::Verify, "Invalid qb header")));
+    }
+    Ok((input, Header {
+        bgra: color_format == 1,
+        right_handed: z_axis == 1,
+        compressed: compressed == 1,
+        num_matrices,
+    }))
+}
+
+fn matrix_volume(input: &[u8], size: Vector3<u32>) -> Result<usize, Err<nom::error::Error<&[u8]>>> {
+    let too_big = || Err::Error(nom::error::Error::from_error_kind(input, ErrorKind::TooLarge));
+    let volume = (size.x as u64) * (size.y as u64) * (size.z as u64);
+    usize::try_from(volume).map_err(|_| too_big())
+}
+
+fn parse_uncompressed(input: &[u8], size: Vector3<u32>) -> IResult<&[u8], Vec<(Vector3<u32>, u32)>> {
+    let volume = matrix_volume(input, size)?;
+    // Every voxel takes 4 bytes, refuse sizes that can't be real instead of trying to allocate them
+    if volume > input.len() / 4 {
+        return Err(Err::Error(nom::error::Error::from_error_kind(input, ErrorKind::Eof)));
+    }
+    let (input, data) = count(le_u32, volume)(input)?;
+    let (sx, sy) = (size.x as usize, size.y as usize);
+    let voxels = data.into_iter().enumerate().map(|(i, color)| {
+        // Every coordinate is smaller than its size, so it fits back in a u32
+        let pos = Vector3::new(i % sx, (i / sx) % sy, i / (sx * sy)).map(|x| x as u32);
+        (pos, color)
+    }).collect();
+    Ok((input, voxels))
+}
+
+fn parse_compressed(mut input: &[u8], size: Vector3<u32>) -> IResult<&[u8], Vec<(Vector3<u32>, u32)>> {
+    let slice_len = matrix_volume(input, Vector3::new(size.x, size.y, 1))?;
+    let mut voxels = Vec::new();
+    for z in 0..size.z {
+        let mut index = 0usize;
+        loop {
+            let (rest, data) = le_u32(input)?;
+            input = rest;
+            let (run, color) = match data {
+                NEXTSLICEFLAG => break,
+                CODEFLAG => {
+                    let (rest, (run, color)) = tuple((le_u32, le_u32))(input)?;
+                    input = rest;
+                    (run as usize, color)
+                },
+                color => (1, color),
+            };
+            if index + run > slice_len {
+                return Err(Err::Error(nom::error::Error::from_error_kind(input, ErrorKind::TooLarge)));
+            }
+            // Runs of empty voxels can cover the whole slice, skip them instead of materializing them
+            if color.to_le_bytes()[3] != 0 {
+                let sx = size.x as usize;
+                for i in index..index + run {
+                    voxels.push((Vector3::new((i % sx) as u32, (i / sx) as u32, z), color));
+                }
+            }
+            index += run;
+        }
+    }
+    Ok((input, voxels))
+}
+
+fn parse_matrix(header: Header) -> impl FnMut(&[u8]) -> IResult<&[u8], Matrix> {
+    move |input: &[u8]| {
+        let (input, name_len) = parse_u8(input)?;
+        let (input, _name) = take(name_len)(input)?;
+        let (input, (sx, sy, sz, px, py, pz)) = tuple((le_u32, le_u32, le_u32, le_i32, le_i32, le_i32))(input)?;
+        let size = Vector3::new(sx, sy, sz);
+        let (input, voxels) = if header.compressed {
+            parse_compressed(input, size)?
+        } else {
+            parse_uncompressed(input, size)?
+        };
+        Ok((input, Matrix {
+            size,
+            pos: Vector3::new(px, py, pz),
+            voxels,
+        }))
+    }
+}
+
+pub fn parse_scene(input: &[u8]) -> IResult<&[u8], Scene> {
+    let (mut input, header) = parse_header(input)?;
+
+    let mut palette: HashMap<[u8; 3], u32> = HashMap::new();
+    let mut colors = Vec::new();
+    let mut placed = Vec::new();
+    for _ in 0..header.num_matrices {
+        let (rest, matrix) = parse_matrix(header)(input)?;
+        input = rest;
+
+        for (pos, color) in matrix.voxels {
+            let [a, b, c, alpha] = color.to_le_bytes();
+            // alpha is either 0 (empty) or a visibility mask
+            if alpha == 0 {
+                continue;
+            }
+            let rgb = if header.bgra { [c, b, a] } else { [a, b, c] };
+            let index = *palette.entry(rgb).or_insert_with(|| {
+                colors.push(Color::new(rgb[0], rgb[1], rgb[2]));
+                colors.len() as u32 - 1
+            });
+
+            // i64 fits any u32 position plus any i32 offset
+            let pos = pos.cast::<i64>().unwrap() + matrix.pos.cast::<i64>().unwrap();
+            // Our world is right-handed, mirror the z axis of left-handed files
+            let pos = if header.right_handed {
+                pos
+            } else {
+                Vector3::new(pos.x, pos.y, -pos.z)
+            };
+            placed.push((pos, index));
+        }
+        log::debug!("qb matrix {:?} at {:?}", matrix.size, matrix.pos);
+    }
+
+    let min = placed.iter().fold(Vector3::new(i64::MAX, i64::MAX, i64::MAX), |a, (p, _c)| {
+        Vector3::new(a.x.min(p.x), a.y.min(p.y), a.z.min(p.z))
+    });
+    let mut grid_size = Vector3::new(0, 0, 0);
+    let mut voxels = Vec::with_capacity(placed.len());
+    for (pos, color) in placed {
+        // The grid size is one more than the biggest position, so that must fit a u32 too
+        let pos = (pos - min).cast::<u32>()
+            .filter(|p| p.x < u32::MAX && p.y < u32::MAX && p.z < u32::MAX)
+            .ok_or_else(|| Err::Error(nom::error::Error::from_error_kind(input, ErrorKind::TooLarge)))?;
+        grid_size = Vector3::new(grid_size.x.max(pos.x + 1), grid_size.y.max(pos.y + 1), grid_size.z.max(pos.z + 1));
+        voxels.push(Voxel { pos, color });
+    }
+
+    let (voxels, issues) = place_voxels(voxels);
+    Ok((input, Scene {
+        voxels,
+        colors,
+        materials: Vec::new(),
+        grid_size,
+        issues,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: u32 = 0xff0000ff;
+    const BLUE: u32 = 0xffff0000;
+
+    fn file(compressed: bool, matrices: &[Vec<u8>]) -> Vec<u8> {
+        let header = [0x0101, 0, 1, compressed as u32, 0, matrices.len() as u32];
+        let mut data: Vec<u8> = header.iter().flat_map(|x| x.to_le_bytes()).collect();
+        data.extend(matrices.concat());
+        data
+    }
+
+    fn matrix(size: [u32; 3], pos: [i32; 3], data: &[u32]) -> Vec<u8> {
+        let mut res = vec![1, b'm'];
+        res.extend(size.iter().flat_map(|x| x.to_le_bytes()));
+        res.extend(pos.iter().flat_map(|x| x.to_le_bytes()));
+        res.extend(data.iter().flat_map(|x| x.to_le_bytes()));
+        res
+    }
+
+    fn voxels(scene: &Scene) -> Vec<([u32; 3], [u8; 3])> {
+        let mut res: Vec<_> = scene.voxels.iter()
+            .map(|v| {
+                let c = &scene.colors[v.color as usize];
+                (v.pos.into(), [c.r, c.g, c.b])
+            })
+            .collect();
+        res.sort();
+        res
+    }
+
+    #[test]
+    fn uncompressed() {
+        // 2x2x1, the empty voxel must be skipped
+        let data = file(false, &[matrix([2, 2, 1], [0, 0, 0], &[RED, 0, BLUE, RED])]);
+        let (rest, scene) = parse_scene(&data).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(scene.grid_size, Vector3::new(2, 2, 1));
+        assert_eq!(voxels(&scene), [([0, 0, 0], [255, 0, 0]), ([0, 1, 0], [0, 0, 255]), ([1, 1, 0], [255, 0, 0])]);
+        assert_eq!(scene.colors.len(), 2);
+    }
+
+    #[test]
+    fn compressed() {
+        // 3x1x2: a run of 2 and a single voxel, then a slice with one empty and two blue voxels
+        let slices = [CODEFLAG, 2, RED, 0, NEXTSLICEFLAG, CODEFLAG, 1, 0, CODEFLAG, 2, BLUE, NEXTSLICEFLAG];
+        let data = file(true, &[matrix([3, 1, 2], [0, 0, 0], &slices)]);
+        let (rest, scene) = parse_scene(&data).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(voxels(&scene), [
+            ([0, 0, 0], [255, 0, 0]), ([1, 0, 0], [255, 0, 0]),
+            ([1, 0, 1], [0, 0, 255]), ([2, 0, 1], [0, 0, 255]),
+        ]);
+    }
+
+    #[test]
+    fn matrix_offsets() {
+        let data = file(false, &[
+            matrix([1, 1, 1], [-5, 0, 0], &[RED]),
+            matrix([1, 1, 1], [5, 2, 0], &[BLUE]),
+        ]);
+        let (_rest, scene) = parse_scene(&data).unwrap();
+        assert_eq!(scene.grid_size, Vector3::new(11, 3, 1));
+        assert_eq!(voxels(&scene), [([0, 0, 0], [255, 0, 0]), ([10, 2, 0], [0, 0, 255])]);
+    }
+
+    #[test]
+    fn huge_empty_run() {
+        // One run clears almost all of a 65536 * 65536 slice, it must not be expanded voxel by voxel
+        let slices = [RED, CODEFLAG, u32::MAX - 1, 0, RED, NEXTSLICEFLAG];
+        let data = file(true, &[matrix([65536, 65536, 1], [0, 0, 0], &slices)]);
+        let (_rest, scene) = parse_scene(&data).unwrap();
+        assert_eq!(voxels(&scene), [([0, 0, 0], [255, 0, 0]), ([65535, 65535, 0], [255, 0, 0])]);
+    }
+
+    #[test]
+    fn run_past_the_slice() {
+        let slices = [CODEFLAG, 4, RED, NEXTSLICEFLAG];
+        let data = file(true, &[matrix([3, 1, 1], [0, 0, 0], &slices)]);
+        assert!(parse_scene(&data).is_err());
+    }
+
+    #[test]
+    fn too_large() {
+        // Two voxels further apart than any grid size
+        let data = file(false, &[
+            matrix([1, 1, 1], [i32::MIN, 0, 0], &[RED]),
+            matrix([1, 1, 1], [i32::MAX, 0, 0], &[RED]),
+        ]);
+        assert!(parse_scene(&data).is_err());
+    }
+}
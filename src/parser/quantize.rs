@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+
+use crate::color::Color;
+
+struct ColorBox {
+    // Unique colors with how many times they appear
+    colors: Vec<([u8; 3], u32)>,
+}
+
+impl ColorBox {
+    fn channel_range(&self, channel: usize) -> u8 {
+        let min = self.colors.iter().map(|(c, _)| c[channel]).min().unwrap_or(0);
+        let max = self.colors.iter().map(|(c, _)| c[channel]).max().unwrap_or(0);
+        max - min
+    }
+
+    fn widest_channel(&self) -> (usize, u8) {
+        (0..3).map(|c| (c, self.channel_range(c)))
+            .max_by_key(|(_c, range)| *range)
+            .unwrap()
+    }
+
+    /// Splits at the weighted median of the widest channel
+    fn split(mut self) -> (ColorBox, ColorBox) {
+        let (channel, _range) = self.widest_channel();
+        self.colors.sort_unstable_by_key(|(c, _)| c[channel]);
+        let total: u64 = self.colors.iter().map(|(_, n)| *n as u64).sum();
+        let mut acc = 0u64;
+        let mut mid = self.colors.iter()
+            .position(|(_, n)| {
+                acc += *n as u64;
+                acc * 2 >= total
+            })
+            .unwrap_or(0) + 1;
+        // Both halves must keep at least one color
+        mid = mid.clamp(1, self.colors.len() - 1);
+        let upper = self.colors.split_off(mid);
+        (self, ColorBox { colors: upper })
+    }
+
+    fn average(&self) -> Color {
+        let mut sum = [0u64; 3];
+        let mut total = 0u64;
+        for (c, n) in self.colors.iter() {
+            for i in 0..3 {
+                sum[i] += c[i] as u64 * *n as u64;
+            }
+            total += *n as u64;
+        }
+        let avg = |i: usize| ((sum[i] + total / 2) / total) as u8;
+        Color::new(avg(0), avg(1), avg(2))
+    }
+}
+
+/// Median cut quantization, returns the palette and the palette index of every input color.
+/// Alpha is ignored, every color in the palette is opaque.
+pub fn quantize(colors: &[Color], max_colors: usize) -> (Vec<Color>, Vec<u32>) {
+    let mut counts: HashMap<[u8; 3], u32> = HashMap::new();
+    for c in colors {
+        *counts.entry([c.r, c.g, c.b]).or_insert(0) += 1;
+    }
+
+    let mut boxes = vec![ColorBox { colors: counts.into_iter().collect() }];
+    while boxes.len() < max_colors {
+        // Always split the box that covers the widest range
+        let candidate = boxes.iter().enumerate()
+            .filter(|(_i, b)| b.colors.len() > 1)
+            .max_by_key(|(_i, b)| b.widest_channel().1)
+            .map(|(i, _b)| i);
+        let Some(index) = candidate else {
+            break;
+        };
+        let (a, b) = boxes.swap_remove(index).split();
+        boxes.push(a);
+        boxes.push(b);
+    }
+
+    let mut palette = Vec::with_capacity(boxes.len());
+    let mut mapping = HashMap::new();
+    for (i, b) in boxes.iter().filter(|b| !b.colors.is_empty()).enumerate() {
+        palette.push(b.average());
+        for (c, _n) in b.colors.iter() {
+            mapping.insert(*c, i as u32);
+        }
+    }
+
+    let indices = colors.iter().map(|c| mapping[&[c.r, c.g, c.b]]).collect();
+    (palette, indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn few_colors_are_kept() {
+        let colors = [Color::new(1, 2, 3), Color::new(200, 0, 0), Color::new(1, 2, 3)];
+        let (palette, indices) = quantize(&colors, 255);
+        assert_eq!(palette.len(), 2);
+        let mapped: Vec<_> = indices.iter().map(|i| palette[*i as usize]).collect();
+        assert_eq!(mapped, colors);
+    }
+
+    #[test]
+    fn clusters() {
+        // Two groups of similar colors, each one becomes its average
+        let colors = [
+            Color::new(0, 0, 10), Color::new(0, 0, 20), Color::new(0, 0, 30),
+            Color::new(250, 0, 0), Color::new(240, 0, 0),
+        ];
+        let (palette, indices) = quantize(&colors, 2);
+        assert_eq!(palette.len(), 2);
+        assert_eq!(indices[0], indices[2]);
+        assert_eq!(indices[3], indices[4]);
+        assert_eq!(palette[indices[0] as usize], Color::new(0, 0, 20));
+        assert_eq!(palette[indices[3] as usize], Color::new(245, 0, 0));
+    }
+
+    #[test]
+    fn single_color_is_weighted() {
+        let colors = [Color::new(0, 0, 0), Color::new(0, 0, 0), Color::new(0, 0, 0), Color::new(200, 100, 40)];
+        let (palette, indices) = quantize(&colors, 1);
+        assert_eq!(palette, [Color::new(50, 25, 10)]);
+        assert_eq!(indices, [0; 4]);
+    }
+}
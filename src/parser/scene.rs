@@ -1,18 +1,178 @@
+use std::fmt;
+
 use cgmath::Vector3;
 
-use crate::color::Color;
+use crate::{color::Color, material::Material};
+
+use super::{error::ParseError, grid::VoxelGrid};
+
+// Used by `Validation::Repair` for voxels pointing outside of the palette
+const FALLBACK_COLOR: Color = Color { r: 255, g: 0, b: 255, a: 255 };
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Voxel {
     pub pos: Vector3<u32>,
     pub color: u32,
 }
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Scene {
-    pub voxels: Vec<Voxel>,
+    pub voxels: VoxelGrid,
     pub colors: Vec<Color>,
+    /// Indexed like `colors`, missing entries use the default material
+    pub materials: Vec<Material>,
     pub grid_size: Vector3<u32>,
-}
\ No newline at end of file
+    /// Problems the parser already worked around, `validate` reports them
+    pub issues: Vec<SceneIssue>,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Validation {
+    /// Any problem makes the scene invalid
+    Strict,
+    /// Fix what can be fixed: the grid grows to fit every voxel and missing colors are replaced
+    Repair,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SceneIssue {
+    OutOfBounds { pos: Vector3<u32> },
+    MissingColor { pos: Vector3<u32>, color: u32 },
+    Duplicate { pos: Vector3<u32> },
+    /// Palette indices that no color defines, they are left black
+    PaletteGap { from: u32, to: u32 },
+    /// The last definition wins
+    DuplicateColor { index: u32 },
+    /// Defined after a color with a higher index
+    UnorderedColor { index: u32 },
+}
+
+impl fmt::Display for SceneIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneIssue::OutOfBounds { pos } => write!(f, "voxel {:?} is outside of the grid", (pos.x, pos.y, pos.z)),
+            SceneIssue::MissingColor { pos, color } => write!(f, "voxel {:?} uses missing color {color}", (pos.x, pos.y, pos.z)),
+            SceneIssue::Duplicate { pos } => write!(f, "more than one voxel at {:?}", (pos.x, pos.y, pos.z)),
+            SceneIssue::PaletteGap { from, to } => write!(f, "palette has no colors from {from} to {to}"),
+            SceneIssue::DuplicateColor { index } => write!(f, "color {index} is defined more than once"),
+            SceneIssue::UnorderedColor { index } => write!(f, "color {index} is out of order"),
+        }
+    }
+}
+
+/// Fills a grid with the voxels of a file, later voxels replace earlier ones at the same position.
+/// Every replaced voxel is reported, see `Scene::issues`
+pub(super) fn place_voxels(voxels: impl IntoIterator<Item = Voxel>) -> (VoxelGrid, Vec<SceneIssue>) {
+    let mut grid = VoxelGrid::new();
+    let mut issues = Vec::new();
+    for voxel in voxels {
+        if grid.set(voxel.pos, voxel.color).is_some() {
+            issues.push(SceneIssue::Duplicate { pos: voxel.pos });
+        }
+    }
+    (grid, issues)
+}
+
+impl Scene {
+    /// Voxels of translucent colors need to be blended with what's behind them
+    pub fn is_translucent(&self, color: u32) -> bool {
+        let opaque_color = self.colors.get(color as usize).map_or(true, |c| c.is_opaque());
+        let transparent_material = self.materials.get(color as usize).map_or(false, |m| m.transparency > 0.0);
+        !opaque_color || transparent_material
+    }
+
+    /// Checks that the scene can be safely rendered, returns every problem that was found.
+    /// In strict mode finding any problem is an error, otherwise the scene is fixed in place.
+    pub fn validate(&mut self, mode: Validation) -> Result<Vec<SceneIssue>, ParseError> {
+        let mut issues = std::mem::take(&mut self.issues);
+        for voxel in self.voxels.iter() {
+            let pos = voxel.pos;
+            if pos.x >= self.grid_size.x || pos.y >= self.grid_size.y || pos.z >= self.grid_size.z {
+                issues.push(SceneIssue::OutOfBounds { pos });
+            }
+            if voxel.color as usize >= self.colors.len() {
+                issues.push(SceneIssue::MissingColor { pos, color: voxel.color });
+            }
+        }
+
+        if issues.is_empty() {
+            return Ok(issues);
+        }
+        if mode == Validation::Strict {
+            return Err(ParseError::InvalidScene(issues));
+        }
+
+        let missing = self.colors.len() as u32;
+        let broken: Vec<Voxel> = self.voxels.iter().filter(|x| x.color >= missing).collect();
+        if !broken.is_empty() {
+            self.colors.push(FALLBACK_COLOR);
+            for voxel in broken {
+                self.voxels.set(voxel.pos, missing);
+            }
+        }
+        // No grid can contain a voxel at u32::MAX
+        let unplaceable: Vec<_> = self.voxels.iter()
+            .filter(|x| x.pos.x == u32::MAX || x.pos.y == u32::MAX || x.pos.z == u32::MAX)
+            .collect();
+        for voxel in unplaceable {
+            self.voxels.remove(voxel.pos);
+        }
+        if let Some((_min, max)) = self.voxels.bounds() {
+            self.grid_size = self.grid_size.zip(max, |size, pos| size.max(pos + 1));
+        }
+        Ok(issues)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scene(voxels: &[([u32; 3], u32)]) -> Scene {
+        let (voxels, issues) = place_voxels(voxels.iter().map(|(pos, color)| Voxel { pos: Vector3::from(*pos), color: *color }));
+        Scene {
+            voxels,
+            colors: vec![Color::new(1, 2, 3)],
+            materials: Vec::new(),
+            grid_size: Vector3::new(2, 2, 2),
+            issues,
+        }
+    }
+
+    #[test]
+    fn valid() {
+        let mut scene = scene(&[([0, 0, 0], 0), ([1, 1, 1], 0)]);
+        let original = scene.clone();
+        assert_eq!(scene.validate(Validation::Strict).unwrap(), []);
+        assert_eq!(scene, original);
+    }
+
+    #[test]
+    fn strict() {
+        let mut broken = scene(&[([0, 0, 0], 0), ([0, 0, 0], 0), ([5, 0, 0], 0), ([1, 0, 0], 3)]);
+        let Err(ParseError::InvalidScene(issues)) = broken.validate(Validation::Strict) else { panic!() };
+        assert_eq!(issues, [
+            SceneIssue::Duplicate { pos: Vector3::new(0, 0, 0) },
+            SceneIssue::MissingColor { pos: Vector3::new(1, 0, 0), color: 3 },
+            SceneIssue::OutOfBounds { pos: Vector3::new(5, 0, 0) },
+        ]);
+
+        // Issues found by the parser count too
+        let mut palette = scene(&[]);
+        palette.issues.push(SceneIssue::PaletteGap { from: 1, to: 2 });
+        assert!(palette.validate(Validation::Strict).is_err());
+    }
+
+    #[test]
+    fn repair() {
+        let mut scene = scene(&[([0, 0, 0], 0), ([0, 0, 0], 0), ([5, 0, 0], 0), ([1, 0, 0], 3), ([u32::MAX, 0, 0], 0)]);
+        scene.issues.push(SceneIssue::DuplicateColor { index: 0 });
+        assert_eq!(scene.validate(Validation::Repair).unwrap().len(), 5);
+        assert_eq!(scene.voxels.len(), 3);
+        assert_eq!(scene.grid_size, Vector3::new(6, 2, 2));
+        assert_eq!(scene.colors[scene.voxels.get(Vector3::new(1, 0, 0)).unwrap() as usize], FALLBACK_COLOR);
+        // Everything has been reported and fixed
+        assert_eq!(scene.validate(Validation::Strict).unwrap(), []);
+    }
+}
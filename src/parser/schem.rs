@@ -0,0 +1,341 @@
+use std::{collections::HashMap, io::Read};
+
+use anyhow::Context;
+use cgmath::Vector3;
+use flate2::read::GzDecoder;
+use nom::Finish;
+
+use crate::{color::Color, material::{Material, MaterialKind}};
+
+use super::{nbt::{self, Tag}, scene::{Voxel, Scene}};
+
+// Sponge schematic format (versions 1 to 3)
+// https://github.com/SpongePowered/Schematic-Specification
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+// Decompressed size limit, a small gzip file can expand to gigabytes
+const MAX_DECOMPRESSED: u64 = 256 << 20;
+
+const AIR_BLOCKS: &[&str] = &["air", "cave_air", "void_air", "structure_void", "barrier", "light"];
+
+const DYES: &[(&str, u32)] = &[
+    ("light_blue", 0x3AB3DA), ("light_gray", 0x9D9D97),
+    ("white", 0xF9FFFE), ("orange", 0xF9801D), ("magenta", 0xC74EBD), ("yellow", 0xFED83D),
+    ("lime", 0x80C71F), ("pink", 0xF38BAA), ("gray", 0x474F52), ("cyan", 0x169C9C),
+    ("purple", 0x8932B8), ("blue", 0x3C44AA), ("brown", 0x835432), ("green", 0x5E7C16),
+    ("red", 0xB02E26), ("black", 0x1D1D21),
+];
+// Blocks that take the color of their dye
+const DYED_BLOCKS: &[&str] = &["wool", "concrete", "terracotta", "glass", "carpet", "bed", "banner", "candle", "shulker_box"];
+
+// Keywords are matched against whole words of the block name,
+// the one with the most words wins (end_stone over stone), then the first in order
+const BLOCK_COLORS: &[(&str, u32)] = &[
+    ("grass_block", 0x5FA03C), ("leaves", 0x3C7828), ("grass", 0x5FA03C), ("fern", 0x4F8A32), ("vine", 0x3C7828),
+    ("coarse_dirt", 0x77553B), ("dirt", 0x86604A), ("farmland", 0x6E4B2F), ("mud", 0x3C3837),
+    ("red_sand", 0xBE6621), ("sandstone", 0xD8CB9B), ("sand", 0xDBCFA3), ("gravel", 0x837F7E), ("clay", 0xA0A6B3),
+    ("dark_oak", 0x42302A), ("oak", 0xA2824E), ("spruce", 0x72542F), ("birch", 0xC4B37B), ("jungle", 0xA07350),
+    ("acacia", 0xA85A32), ("mangrove", 0x753630), ("cherry", 0xE2B2AC), ("bamboo", 0xC1AD50),
+    ("crimson", 0x653147), ("warped", 0x2B6963),
+    ("deepslate", 0x505055), ("cobblestone", 0x7A7A7A), ("mossy", 0x6A7A5A), ("andesite", 0x888888),
+    ("diorite", 0xBCBCBC), ("granite", 0x956755), ("stone", 0x7D7D7D), ("bricks", 0x966153), ("brick", 0x966153),
+    ("quartz", 0xEBE5DE), ("obsidian", 0x0F0A18), ("netherrack", 0x612626), ("end_stone", 0xDBDE9E),
+    ("snow", 0xF9FEFE), ("ice", 0x91B7FD), ("water", 0x3F76E4), ("lava", 0xCF5C14),
+    ("iron", 0xDCDCDC), ("gold", 0xF6D03D), ("diamond", 0x62EDE4), ("emerald", 0x2ACB57),
+    ("lapis", 0x1F43B0), ("redstone", 0xAF1805), ("copper", 0xC06B4F), ("coal", 0x2E2E2E),
+    ("bookshelf", 0x6B5536), ("pumpkin", 0xC6761C), ("melon", 0x6F9A2B), ("hay", 0xA68B0C),
+    ("glowstone", 0xFBDA74), ("sea_lantern", 0xACC8BE), ("lantern", 0xE9B95E), ("torch", 0xFFD86C),
+    ("shroomlight", 0xF19D4F), ("glass", 0xD8E8F0),
+];
+const TRANSLUCENT_BLOCKS: &[&str] = &["glass", "water", "ice"];
+const EMISSIVE_BLOCKS: &[&str] = &["lava", "glowstone", "sea_lantern", "lantern", "torch", "shroomlight", "magma"];
+
+
+fn hex_color(rgb: u32) -> Color {
+    let [b, g, r, _] = rgb.to_le_bytes();
+    Color::new(r, g, b)
+}
+
+/// Invents a stable color for blocks we don't know about
+fn hashed_color(name: &str) -> Color {
+    // FNV-1a
+    let hash = name.bytes().fold(0x811c9dc5u32, |h, b| (h ^ b as u32).wrapping_mul(0x01000193));
+    let [r, g, b, _] = hash.to_le_bytes();
+    // Keep it away from pure black and white
+    let squash = |x: u8| 48 + (x as u32 * 160 / 255) as u8;
+    Color::new(squash(r), squash(g), squash(b))
+}
+
+/// Whether `key` is a sequence of whole words in `name`: "red_sand" is in "red_sand_slab" but not in "red_sandstone"
+fn has_words(name: &str, key: &str) -> bool {
+    let words: Vec<_> = name.split('_').collect();
+    let key: Vec<_> = key.split('_').collect();
+    words.windows(key.len()).any(|x| x == key)
+}
+
+fn block_look(name: &str) -> (Color, Material) {
+    let dye = DYES.iter().find(|(dye, _)| has_words(name, dye));
+    let dyed = DYED_BLOCKS.iter().any(|x| has_words(name, x));
+    let word_count = |key: &str| key.split('_').count();
+    let mut color = match dye {
+        Some((_, rgb)) if dyed => hex_color(*rgb),
+        _ => BLOCK_COLORS.iter()
+            .filter(|(key, _)| has_words(name, key))
+            .fold(None, |best: Option<&(&str, u32)>, x| match best {
+                Some(best) if word_count(best.0) >= word_count(x.0) => Some(best),
+                _ => Some(x),
+            })
+            .map_or_else(|| hashed_color(name), |(_, rgb)| hex_color(*rgb)),
+    };
+
+    let mut material = Material::default();
+    if TRANSLUCENT_BLOCKS.iter().any(|x| has_words(name, x)) {
+        color.a = if has_words(name, "ice") { 180 } else { 120 };
+    }
+    if EMISSIVE_BLOCKS.iter().any(|x| has_words(name, x)) {
+        material.kind = MaterialKind::Emissive;
+        material.emission = 1.0;
+    }
+    (color, material)
+}
+
+fn dimension(schematic: &Tag, key: &str) -> anyhow::Result<u32> {
+    let value = schematic.get(key)
+        .and_then(Tag::as_int)
+        .with_context(|| format!("Missing schematic {key}"))?;
+    // Stored as shorts, but they're meant to be unsigned
+    Ok(value as u16 as u32)
+}
+
+/// Gzip alone is not a signature, the compressed data must also start like NBT (a compound tag)
+pub fn sniff(data: &[u8]) -> bool {
+    let mut first = [0u8];
+    data.starts_with(GZIP_MAGIC) && GzDecoder::new(data).read_exact(&mut first).is_ok() && first[0] == nbt::COMPOUND_ID
+}
+
+fn decompress(data: &[u8], limit: u64) -> anyhow::Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    // One byte more than the limit tells a file that fits exactly from a bigger one
+    GzDecoder::new(data).take(limit + 1).read_to_end(&mut decompressed).context("Invalid gzip data")?;
+    anyhow::ensure!(decompressed.len() as u64 <= limit, "Decompressed schematic larger than {} MiB", limit >> 20);
+    Ok(decompressed)
+}
+
+pub fn parse_scene(data: &[u8]) -> anyhow::Result<Scene> {
+    let decompressed;
+    let data = if data.starts_with(GZIP_MAGIC) {
+        decompressed = decompress(data, MAX_DECOMPRESSED)?;
+        &decompressed
+    } else {
+        data
+    };
+
+    let (_rest, (_name, root)) = nbt::parse_root(data)
+        .map_err(|e| e.map_input(|x| format!("offset {}", data.len() - x.len())))
+        .finish()
+        .context("Invalid NBT data")?;
+
+    // Version 3 nests everything in a "Schematic" compound
+    let schematic = root.get("Schematic").unwrap_or(&root);
+    let version = schematic.get("Version").and_then(Tag::as_int).unwrap_or(1);
+    let width = dimension(schematic, "Width")?;
+    let height = dimension(schematic, "Height")?;
+    let length = dimension(schematic, "Length")?;
+
+    let (palette, block_data) = if version >= 3 {
+        let blocks = schematic.get("Blocks").context("Missing schematic Blocks")?;
+        (blocks.get("Palette"), blocks.get("Data"))
+    } else {
+        (schematic.get("Palette"), schematic.get("BlockData"))
+    };
+    let palette = match palette {
+        Some(Tag::Compound(x)) => x,
+        _ => anyhow::bail!("Missing schematic palette"),
+    };
+    let block_data = match block_data {
+        Some(Tag::ByteArray(x)) => x,
+        _ => anyhow::bail!("Missing schematic block data"),
+    };
+
+    // Blocks with different states (ex. minecraft:oak_stairs[facing=north]) share the same color
+    let mut colors = Vec::new();
+    let mut materials = Vec::new();
+    let mut names: HashMap<&str, u32> = HashMap::new();
+    let mut block_colors: HashMap<u32, u32> = HashMap::new();
+    for (block, id) in palette.iter() {
+        let id = id.as_int().context("Invalid palette entry")? as u32;
+        let name = block.split('[').next().unwrap_or(block);
+        let name = name.strip_prefix("minecraft:").unwrap_or(name);
+        if AIR_BLOCKS.contains(&name) {
+            continue;
+        }
+        let index = *names.entry(name).or_insert_with(|| {
+            let (color, material) = block_look(name);
+            colors.push(color);
+            materials.push(material);
+            colors.len() as u32 - 1
+        });
+        block_colors.insert(id, index);
+    }
+
+    let mut voxels = Vec::new();
+    let mut data = block_data.iter();
+    let layer = width as usize * length as usize;
+    for i in 0..layer * height as usize {
+        // Block ids are stored as varints
+        let mut id = 0u32;
+        let mut shift = 0;
+        loop {
+            let byte = *data.next().context("Block data too short")?;
+            id |= ((byte & 0x7f) as u32) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+            anyhow::ensure!(shift < 32, "Invalid block data varint");
+        }
+
+        if let Some(&color) = block_colors.get(&id) {
+            let x = i % width as usize;
+            let z = (i / width as usize) % length as usize;
+            let y = i / layer;
+            voxels.push(Voxel {
+                pos: Vector3::new(x as u32, y as u32, z as u32),
+                color,
+            });
+        }
+    }
+
+    Ok(Scene {
+        voxels: voxels.into_iter().collect(),
+        colors,
+        materials,
+        grid_size: Vector3::new(width, height, length),
+        issues: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    fn named(id: u8, name: &str, payload: &[u8]) -> Vec<u8> {
+        let mut res = vec![id];
+        res.extend((name.len() as u16).to_be_bytes());
+        res.extend(name.as_bytes());
+        res.extend(payload);
+        res
+    }
+
+    fn compound(entries: &[Vec<u8>]) -> Vec<u8> {
+        [entries.concat(), vec![0]].concat()
+    }
+
+    fn short(name: &str, x: u16) -> Vec<u8> {
+        named(2, name, &x.to_be_bytes())
+    }
+
+    fn int(name: &str, x: i32) -> Vec<u8> {
+        named(3, name, &x.to_be_bytes())
+    }
+
+    fn byte_array(name: &str, data: &[u8]) -> Vec<u8> {
+        named(7, name, &[&(data.len() as i32).to_be_bytes()[..], data].concat())
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// 2x1x2 schematic: red sandstone, air, then two oak stairs with different states
+    fn schematic(version: i32) -> Vec<u8> {
+        let palette = compound(&[
+            int("minecraft:air", 0),
+            int("minecraft:red_sandstone", 1),
+            int("minecraft:oak_stairs[facing=north]", 2),
+            int("minecraft:oak_stairs[facing=south]", 200),
+        ]);
+        // 200 takes two varint bytes
+        let data = [1, 0, 2, 0xc8, 0x01];
+        let blocks = if version >= 3 {
+            vec![named(10, "Blocks", &compound(&[named(10, "Palette", &palette), byte_array("Data", &data)]))]
+        } else {
+            vec![named(10, "Palette", &palette), byte_array("BlockData", &data)]
+        };
+        let schematic = compound(&[
+            vec![int("Version", version), short("Width", 2), short("Height", 1), short("Length", 2)],
+            blocks,
+        ].concat());
+        if version >= 3 {
+            named(10, "", &compound(&[named(10, "Schematic", &schematic)]))
+        } else {
+            named(10, "Schematic", &schematic)
+        }
+    }
+
+    fn voxels(scene: &Scene) -> Vec<([u32; 3], u32)> {
+        let mut res: Vec<_> = scene.voxels.iter().map(|v| (v.pos.into(), v.color)).collect();
+        res.sort();
+        res
+    }
+
+    #[test]
+    fn versions() {
+        for version in [2, 3] {
+            let scene = parse_scene(&gzip(&schematic(version))).unwrap();
+            assert_eq!(scene.grid_size, Vector3::new(2, 1, 2));
+            // Air is skipped, both stairs share one color
+            assert_eq!(scene.colors.len(), 2);
+            let stairs = scene.voxels.get(Vector3::new(0, 0, 1)).unwrap();
+            assert_eq!(voxels(&scene), [([0, 0, 0], 1 - stairs), ([0, 0, 1], stairs), ([1, 0, 1], stairs)]);
+        }
+    }
+
+    #[test]
+    fn uncompressed() {
+        assert_eq!(parse_scene(&schematic(2)).unwrap().voxels.len(), 3);
+    }
+
+    #[test]
+    fn short_block_data() {
+        let mut data = schematic(2);
+        // Width 2 -> 3
+        let pos = data.windows(5).position(|x| x == b"Width").unwrap();
+        data[pos + 6] = 3;
+        assert!(parse_scene(&data).is_err());
+    }
+
+    #[test]
+    fn sniffing() {
+        assert!(sniff(&gzip(&schematic(2))));
+        assert!(!sniff(&schematic(2)));
+        assert!(!sniff(&gzip(b"grid_size: 1 1 1")));
+    }
+
+    #[test]
+    fn decompression_limit() {
+        let data = gzip(&[0; 1025]);
+        assert!(decompress(&data, 1024).is_err());
+        assert_eq!(decompress(&data, 1025).unwrap().len(), 1025);
+    }
+
+    #[test]
+    fn whole_word_block_names() {
+        let color = |name| block_look(name).0;
+        assert_eq!(color("red_sandstone"), color("sandstone"));
+        assert_eq!(color("red_sand"), hex_color(0xBE6621));
+        assert_eq!(color("end_stone_bricks"), color("end_stone"));
+        assert_ne!(color("end_stone"), color("stone"));
+        assert_eq!(color("light_blue_wool"), hex_color(0x3AB3DA));
+        assert_eq!(color("oak_planks"), hex_color(0xA2824E));
+        assert_eq!(color("dark_oak_planks"), hex_color(0x42302A));
+        // "ice" is not a word of "dice", nor "glass" of "glassy"
+        assert_eq!(color("packed_ice").a, 180);
+        assert!(color("dice_block").is_opaque());
+    }
+}
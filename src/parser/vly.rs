@@ -4,12 +4,15 @@ use cgmath::Vector3;
 use nom::{
     IResult,
     bytes::complete::tag,
-    character::complete::{multispace0, digit1, space0}, multi::{many0, count}, sequence::{preceded, pair}, combinator::{map_res, recognize, map},
+    character::complete::{multispace0, digit1, space0}, multi::{many0, count}, sequence::{preceded, pair}, combinator::{map_res, recognize, map}, error::ErrorKind,
 };
 
 use crate::color::Color;
 
-use super::scene::{Voxel, Scene};
+use super::scene::{place_voxels, Voxel, Scene, SceneIssue};
+
+// Palettes are built by index, a single huge one must not allocate gigabytes
+const MAX_COLORS: u32 = 1 << 16;
 
 
 struct Header {
@@ -43,23 +46,104 @@ fn parse_voxel(input: &str) -> IResult<&str, Voxel> {
     })(input)
 }
 
-fn parse_color(input: &str) -> IResult<&str, Color> {
+fn parse_color_index(input: &str) -> IResult<&str, u32> {
+    let (rest, idx) = parse_int(input)?;
+    if idx >= MAX_COLORS {
+        // Failing, instead of ending the palette, so that the error points to the index
+        return Err(nom::Err::Failure(nom::error::Error::new(input.trim_start(), ErrorKind::Verify)));
+    }
+    Ok((rest, idx))
+}
+
+fn parse_color(input: &str) -> IResult<&str, (u32, Color)> {
     let (input, _) = multispace0(input)?;
-    map(pair(parse_int::<u32>, count(parse_int, 3)), |(_idx, rgb)|
-        Color::new(rgb[0], rgb[1], rgb[2])
+    map(pair(parse_color_index, count(parse_int, 3)), |(idx, rgb)|
+        (idx, Color::new(rgb[0], rgb[1], rgb[2]))
     )(input)
 }
 
+/// Places every color at its declared index, the problems found are returned to be reported by `Scene::validate`
+fn build_palette(mut entries: Vec<(u32, Color)>) -> (Vec<Color>, Vec<SceneIssue>) {
+    let mut issues = Vec::new();
+    for pair in entries.windows(2) {
+        if pair[1].0 < pair[0].0 {
+            issues.push(SceneIssue::UnorderedColor { index: pair[1].0 });
+        }
+    }
+    // Stable, so that the last definition of a color still wins
+    entries.sort_by_key(|(idx, _c)| *idx);
+    let mut colors: Vec<Color> = Vec::with_capacity(entries.len());
+    for (idx, color) in entries {
+        let len = colors.len() as u32;
+        if idx > len {
+            // Keep the indices of the following colors
+            issues.push(SceneIssue::PaletteGap { from: len, to: idx - 1 });
+            colors.resize(idx as usize, Color::new(0, 0, 0));
+        }
+        if idx < len {
+            issues.push(SceneIssue::DuplicateColor { index: idx });
+            colors[idx as usize] = color;
+        } else {
+            colors.push(color);
+        }
+    }
+    (colors, issues)
+}
+
 pub fn parse_scene(input: &str) -> IResult<&str, Scene> {
     let (input, header) = parse_header(input)?;
 
     let (input , voxels) = count(parse_voxel, header.voxel_num as _)(input)?;
 
     let (input, colors) = many0(parse_color)(input)?;
+    let (colors, mut issues) = build_palette(colors);
+    let (voxels, duplicates) = place_voxels(voxels);
+    issues.extend(duplicates);
 
-    // TODO: Ensure that all colors are ordered
     Ok((input, Scene {
-        voxels, colors,
+        voxels,
+        colors,
+        materials: Vec::new(),
         grid_size: header.grid_size,
+        issues,
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn palette(text: &str) -> (Vec<Color>, Vec<SceneIssue>) {
+        let input = format!("grid_size: 1 1 1\nvoxel_num: 0\n{text}");
+        let (_rest, scene) = parse_scene(&input).unwrap();
+        (scene.colors, scene.issues)
+    }
+
+    #[test]
+    fn ordered_palette() {
+        let (colors, issues) = palette("0 1 2 3\n1 4 5 6\n");
+        assert_eq!(colors, [Color::new(1, 2, 3), Color::new(4, 5, 6)]);
+        assert!(issues.is_empty());
+    }
+
+    #[test]
+    fn palette_issues() {
+        let (colors, issues) = palette("1 4 5 6\n0 1 2 3\n4 7 8 9\n0 9 9 9\n");
+        assert_eq!(colors, [Color::new(9, 9, 9), Color::new(4, 5, 6), Color::new(0, 0, 0), Color::new(0, 0, 0), Color::new(7, 8, 9)]);
+        assert_eq!(issues, [
+            SceneIssue::UnorderedColor { index: 0 },
+            SceneIssue::UnorderedColor { index: 0 },
+            SceneIssue::DuplicateColor { index: 0 },
+            SceneIssue::PaletteGap { from: 2, to: 3 },
+        ]);
+    }
+
+    #[test]
+    fn huge_index() {
+        let input = "grid_size: 1 1 1\nvoxel_num: 0\n0 1 2 3\n4294967295 1 2 3\n";
+        let err = parse_scene(input).unwrap_err();
+        let nom::Err::Failure(err) = err else { panic!("{err:?}") };
+        // Points to the index itself
+        assert!(err.input.starts_with("4294967295"));
+    }
+}
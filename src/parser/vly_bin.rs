@@ -0,0 +1,146 @@
+use cgmath::Vector3;
+use nom::{
+    IResult,
+    bytes::complete::{tag, take},
+    number::complete::u8 as parse_u8,
+    error::{ErrorKind, ParseError, FromExternalError}, Err,
+};
+
+use crate::color::Color;
+
+use super::scene::{place_voxels, Voxel, Scene};
+
+// Binary flavour of the vly format, everything is stored in scene coordinates (y is up):
+// magic "VLYB", version (u8),
+// grid size (3 varints), palette length (varint), voxel count (varint),
+// palette (r, g, b, a bytes for each color),
+// voxels: position delta from the previous voxel (3 zigzag varints) followed by the color (varint)
+pub const MAGIC_BYTES: &[u8] = b"VLYB";
+pub const VERSION: u8 = 1;
+
+
+/// LEB128 unsigned integer
+fn parse_varint(input: &[u8]) -> IResult<&[u8], u64> {
+    let mut res = 0u64;
+    for (i, byte) in input.iter().enumerate().take(10) {
+        // The 10th byte only has room for the highest bit of a u64
+        if i == 9 && *byte > 1 {
+            return Err(Err::Error(nom::error::Error::from_error_kind(input, ErrorKind::TooLarge)));
+        }
+        res |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((&input[i + 1..], res));
+        }
+    }
+    let kind = if input.len() < 10 { ErrorKind::Eof } else { ErrorKind::TooLarge };
+    Err(Err::Error(nom::error::Error::from_error_kind(input, kind)))
+}
+
+fn parse_u32(input: &[u8]) -> IResult<&[u8], u32> {
+    let (rest, x) = parse_varint(input)?;
+    let x = u32::try_from(x)
+        .map_err(|e| Err::Error(nom::error::Error::from_external_error(input, ErrorKind::TooLarge, e)))?;
+    Ok((rest, x))
+}
+
+fn parse_delta(input: &[u8]) -> IResult<&[u8], i64> {
+    let (input, x) = parse_varint(input)?;
+    // zigzag decoding
+    Ok((input, (x >> 1) as i64 ^ -((x & 1) as i64)))
+}
+
+fn parse_color(input: &[u8]) -> IResult<&[u8], Color> {
+    let (input, rgba) = take(4u32)(input)?;
+    Ok((input, Color::with_alpha(rgba[0], rgba[1], rgba[2], rgba[3])))
+}
+
+pub fn parse_scene(input: &[u8]) -> IResult<&[u8], Scene> {
+    let (input, _magic) = tag(MAGIC_BYTES)(input)?;
+    let (input, version) = parse_u8(input)?;
+    if version != VERSION {
+        return Err(Err::Error(nom::error::Error::from_external_error(input, ErrorKind::Verify, "Unsupported version")));
+    }
+    let (input, (x, y, z)) = nom::sequence::tuple((parse_u32, parse_u32, parse_u32))(input)?;
+    let (input, palette_len) = parse_u32(input)?;
+    let (mut input, voxel_num) = parse_u32(input)?;
+
+    // Don't trust the header for preallocations, every color takes 4 bytes and every voxel at least 4
+    let mut colors = Vec::with_capacity((palette_len as usize).min(input.len() / 4));
+    for _ in 0..palette_len {
+        let (rest, color) = parse_color(input)?;
+        colors.push(color);
+        input = rest;
+    }
+
+    let mut voxels = Vec::with_capacity((voxel_num as usize).min(input.len() / 4));
+    let mut last = Vector3::new(0i64, 0, 0);
+    for _ in 0..voxel_num {
+        let (rest, (dx, dy, dz, color)) = nom::sequence::tuple((parse_delta, parse_delta, parse_delta, parse_u32))(input)?;
+        let pos = [(last.x, dx), (last.y, dy), (last.z, dz)]
+            .map(|(x, d)| x.checked_add(d).and_then(|x| u32::try_from(x).ok()));
+        let [Some(x), Some(y), Some(z)] = pos else {
+            return Err(Err::Error(nom::error::Error::from_error_kind(input, ErrorKind::Verify)));
+        };
+        let pos = Vector3::new(x, y, z);
+        voxels.push(Voxel { pos, color });
+        last = pos.cast().unwrap();
+        input = rest;
+    }
+
+    let (voxels, issues) = place_voxels(voxels);
+    Ok((input, Scene {
+        voxels,
+        colors,
+        materials: Vec::new(),
+        grid_size: Vector3::new(x, y, z),
+        issues,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint_edge_values() {
+        assert_eq!(parse_varint(&[0]), Ok((&[][..], 0)));
+        assert_eq!(parse_varint(&[0x80, 0x01]), Ok((&[][..], 128)));
+        assert_eq!(parse_u32(&[0xff, 0xff, 0xff, 0xff, 0x0f]), Ok((&[][..], u32::MAX)));
+        // u32::MAX + 1
+        assert!(parse_u32(&[0x80, 0x80, 0x80, 0x80, 0x10]).is_err());
+        // Truncated, and longer than any u64
+        assert!(parse_varint(&[0x80]).is_err());
+        assert!(parse_varint(&[0xff; 11]).is_err());
+        // Ten bytes fit a u64 only if the last one is 0 or 1
+        let mut max = [0xff; 10];
+        max[9] = 0x01;
+        assert_eq!(parse_varint(&max), Ok((&[][..], u64::MAX)));
+        max[9] = 0x02;
+        assert!(parse_varint(&max).is_err());
+    }
+
+    #[test]
+    fn zigzag_edge_values() {
+        assert_eq!(parse_delta(&[0]), Ok((&[][..], 0)));
+        assert_eq!(parse_delta(&[1]), Ok((&[][..], -1)));
+        assert_eq!(parse_delta(&[2]), Ok((&[][..], 1)));
+        // The biggest jumps between two positions
+        assert_eq!(parse_delta(&[0xfe, 0xff, 0xff, 0xff, 0x1f]), Ok((&[][..], u32::MAX as i64)));
+        assert_eq!(parse_delta(&[0xfd, 0xff, 0xff, 0xff, 0x1f]), Ok((&[][..], -(u32::MAX as i64))));
+    }
+
+    #[test]
+    fn delta_overflow_is_an_error() {
+        let mut data = MAGIC_BYTES.to_vec();
+        data.extend([VERSION, 1, 1, 1, 0, 2]);
+        // x = 1, then x + i64::MAX
+        let mut huge = [0xff; 10];
+        huge[0] = 0xfe;
+        huge[9] = 0x01;
+        data.extend([2, 0, 0, 0]);
+        data.extend(huge);
+        data.extend([0, 0, 0]);
+        assert_eq!(parse_delta(&huge), Ok((&[][..], i64::MAX)));
+        assert!(parse_scene(&data).is_err());
+    }
+}
@@ -1,18 +1,20 @@
 
+use std::collections::HashMap;
+
 use cgmath::{Vector3, num_traits::ToBytes};
 use nom::{
     IResult,
     bytes::complete::{tag, take},
-    multi::{count, fill},
-    sequence::{preceded, tuple},
+    multi::{count, fill, many0},
+    sequence::{preceded, tuple, pair},
     combinator::map, error::{ParseError, ErrorKind, FromExternalError}, Err,
 };
 
-use crate::color::Color;
+use crate::{color::Color, material::{Material, MaterialKind}};
 
-use super::scene::{Voxel, Scene};
+use super::scene::{place_voxels, Voxel, Scene};
 
-const MAGIC_BYTES: &'static [u8] = b"VOX ";
+pub const MAGIC_BYTES: &'static [u8] = b"VOX ";
 const DEFAULT_PALETTE: &[u32] = &[
     0x00000000, 0xffffffff, 0xffccffff, 0xff99ffff, 0xff66ffff, 0xff33ffff, 0xff00ffff, 0xffffccff, 0xffccccff, 0xff99ccff, 0xff66ccff, 0xff33ccff, 0xff00ccff, 0xffff99ff, 0xffcc99ff, 0xff9999ff,
     0xff6699ff, 0xff3399ff, 0xff0099ff, 0xffff66ff, 0xffcc66ff, 0xff9966ff, 0xff6666ff, 0xff3366ff, 0xff0066ff, 0xffff33ff, 0xffcc33ff, 0xff9933ff, 0xff6633ff, 0xff3333ff, 0xff0033ff, 0xffff00ff,
@@ -37,6 +39,102 @@ struct Header {
     version: u32,
 }
 
+/// Voxel as stored in the file, still in MagicaVoxel coordinates (z is up)
+#[derive(Debug, Clone, Copy)]
+struct RawVoxel {
+    pos: Vector3<u8>,
+    color: u8,
+}
+
+struct Model {
+    size: Vector3<u32>,
+    voxels: Vec<RawVoxel>,
+}
+
+/// Signed permutation matrix as stored in the `_r` attribute of nTRN frames.
+/// Row `i` of the matrix has a single non-zero entry `signs[i]` in column `axes[i]`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Rotation {
+    axes: [usize; 3],
+    signs: [i32; 3],
+}
+
+impl Rotation {
+    const IDENTITY: Rotation = Rotation { axes: [0, 1, 2], signs: [1, 1, 1] };
+
+    fn from_byte(r: u8) -> Option<Self> {
+        // https://github.com/ephtracy/voxel-model/blob/master/MagicaVoxel-file-format-vox-extension.txt
+        let first = (r & 3) as usize;
+        let second = ((r >> 2) & 3) as usize;
+        if first > 2 || second > 2 || first == second {
+            return None;
+        }
+        let third = 3 - first - second;
+        let sign = |bit: u8| if r & (1 << bit) != 0 { -1 } else { 1 };
+        Some(Rotation {
+            axes: [first, second, third],
+            signs: [sign(4), sign(5), sign(6)],
+        })
+    }
+
+    fn apply(&self, v: Vector3<i32>) -> Vector3<i32> {
+        Vector3::new(
+            self.signs[0] * v[self.axes[0]],
+            self.signs[1] * v[self.axes[1]],
+            self.signs[2] * v[self.axes[2]],
+        )
+    }
+
+    /// Returns the rotation equivalent to applying `other` first and then `self`
+    fn then(&self, other: &Rotation) -> Rotation {
+        let mut res = Rotation::IDENTITY;
+        for i in 0..3 {
+            res.axes[i] = other.axes[self.axes[i]];
+            res.signs[i] = self.signs[i] * other.signs[self.axes[i]];
+        }
+        res
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Transform {
+    rotation: Rotation,
+    translation: Vector3<i32>,
+}
+
+impl Transform {
+    const IDENTITY: Transform = Transform { rotation: Rotation::IDENTITY, translation: Vector3::new(0, 0, 0) };
+
+    fn apply(&self, v: Vector3<i32>) -> Vector3<i32> {
+        self.rotation.apply(v) + self.translation
+    }
+
+    /// Transform of a child node (`local`) once it's placed inside of `self`
+    fn then(&self, local: &Transform) -> Transform {
+        Transform {
+            rotation: self.rotation.then(&local.rotation),
+            translation: self.apply(local.translation),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Node {
+    Transform {
+        child: u32,
+        transform: Transform,
+        hidden: bool,
+    },
+    Group {
+        children: Vec<u32>,
+    },
+    Shape {
+        models: Vec<u32>,
+    },
+}
+
+type Dict = HashMap<String, String>;
+
 fn parse_int4(input: &[u8]) -> IResult<&[u8], u32> {
     let (input, version) = take(4u32)(input)?;
 
@@ -60,6 +158,15 @@ fn parse_chunk<'a>(name: &'static [u8]) ->  impl FnMut(&'a [u8]) -> IResult<&'a
     }
 }
 
+/// Parses any chunk (children included), returns its id and its content
+fn parse_any_chunk(input: &[u8]) -> IResult<&[u8], (&[u8], &[u8])> {
+    let (input, (id, data_len, children_len)) = tuple((take(4u32), parse_int4, parse_int4))(input)?;
+    let (input, chunk_data) = take(data_len)(input)?;
+    let (input, _children) = take(children_len)(input)?;
+
+    Ok((input, (id, chunk_data)))
+}
+
 fn check_zero(chunk: &[u8], children: u32) -> Result<(), nom::Err<nom::error::Error<&[u8]>>> {
     if children != 0 {
         return Err(nom::Err::Error(nom::error::Error::from_error_kind(chunk, ErrorKind::NonEmpty)));
@@ -67,38 +174,100 @@ fn check_zero(chunk: &[u8], children: u32) -> Result<(), nom::Err<nom::error::Er
     Ok(())
 }
 
-fn parse_vec3(input: &[u8]) -> IResult<&[u8], Vector3<u32>> {
-    map(count(parse_int4, 3), |x| Vector3::new(x[0], x[2], x[1]))(input)
+/// Keeps errors at the end of a chunk's content inside the chunk, otherwise they would point to the next one
+fn inside<'a, O>(chunk: &'a [u8], result: IResult<&'a [u8], O>) -> IResult<&'a [u8], O> {
+    result.map_err(|e| e.map(|mut e| {
+        if e.input.is_empty() && !chunk.is_empty() {
+            e.input = &chunk[chunk.len() - 1..];
+        }
+        e
+    }))
 }
 
-fn parse_size(input: &[u8]) -> IResult<&[u8], Vector3<u32>> {
-    let (input, (data, children)) = parse_chunk(b"SIZE")(input)?;
-    check_zero(data, children)?;
-    let (data, vec3) = parse_vec3(data)?;
-    check_zero(data, data.len() as _)?;
-
-    Ok((input, vec3))
+fn parse_vec3(input: &[u8]) -> IResult<&[u8], Vector3<u32>> {
+    map(count(parse_int4, 3), |x| Vector3::new(x[0], x[1], x[2]))(input)
 }
 
-fn parse_voxel(input: &[u8]) -> IResult<&[u8], Voxel> {
+fn parse_voxel(input: &[u8]) -> IResult<&[u8], RawVoxel> {
     map(parse_int4, |x| {
         let x = x.to_le_bytes();
-        Voxel {
-            // invert y and z!
-            pos: Vector3::new(x[1] as u32, x[2] as _, x[0] as _),
-            color: x[3] as u32
+        RawVoxel {
+            pos: Vector3::new(x[0], x[1], x[2]),
+            color: x[3],
         }
-})(input)
+    })(input)
+}
+
+fn parse_xyzi(data: &[u8]) -> IResult<&[u8], Vec<RawVoxel>> {
+    let (data, num_voxels) = parse_int4(data)?;
+    // Every voxel takes 4 bytes, don't trust the count for the allocation
+    if num_voxels as usize > data.len() / 4 {
+        return Err(Err::Error(nom::error::Error::from_error_kind(data, ErrorKind::Eof)));
+    }
+    count(parse_voxel, num_voxels as _)(data)
+}
+
+fn parse_string(input: &[u8]) -> IResult<&[u8], String> {
+    let (input, len) = parse_int4(input)?;
+    let (input, data) = take(len)(input)?;
+    Ok((input, String::from_utf8_lossy(data).into_owned()))
+}
+
+fn parse_dict(input: &[u8]) -> IResult<&[u8], Dict> {
+    let (input, len) = parse_int4(input)?;
+    let (input, entries) = count(pair(parse_string, parse_string), len as _)(input)?;
+    Ok((input, entries.into_iter().collect()))
+}
+
+fn parse_frame_transform(frame: &Dict) -> Option<Transform> {
+    let rotation = match frame.get("_r") {
+        Some(r) => Rotation::from_byte(r.trim().parse().ok()?)?,
+        None => Rotation::IDENTITY,
+    };
+    let translation = match frame.get("_t") {
+        Some(t) => {
+            let t = t.split_whitespace()
+                .map(|x| x.parse::<i32>())
+                .collect::<Result<Vec<_>, _>>()
+                .ok()?;
+            if t.len() != 3 {
+                return None;
+            }
+            Vector3::new(t[0], t[1], t[2])
+        },
+        None => Vector3::new(0, 0, 0),
+    };
+    Some(Transform { rotation, translation })
+}
+
+fn parse_transform_node(input: &[u8]) -> IResult<&[u8], (u32, Node)> {
+    let (input, (node_id, attributes, child, _reserved, _layer, num_frames)) =
+        tuple((parse_int4, parse_dict, parse_int4, parse_int4, parse_int4, parse_int4))(input)?;
+    let (input, frames) = count(parse_dict, num_frames as _)(input)?;
+
+    // Animations are not supported, only the first frame is used
+    let transform = match frames.first() {
+        Some(frame) => parse_frame_transform(frame)
+            .ok_or_else(|| Err::Error(nom::error::Error::from_external_error(input, ErrorKind::Verify, "Invalid transform")))?,
+        None => Transform::IDENTITY,
+    };
+    let hidden = attributes.get("_hidden").map_or(false, |x| x == "1");
+
+    Ok((input, (node_id, Node::Transform { child, transform, hidden })))
+}
+
+fn parse_group_node(input: &[u8]) -> IResult<&[u8], (u32, Node)> {
+    let (input, (node_id, _attributes, num_children)) = tuple((parse_int4, parse_dict, parse_int4))(input)?;
+    let (input, children) = count(parse_int4, num_children as _)(input)?;
+
+    Ok((input, (node_id, Node::Group { children })))
 }
 
-fn parse_model(input: &[u8]) -> IResult<&[u8], (Vector3<u32>, Vec<Voxel>)> {
-    let (input, size) = parse_size(input)?;
-    let (input, (chunk, children)) = parse_chunk(b"XYZI")(input)?;
-    check_zero(input, children)?;
-    let (chunk, num_voxels) = parse_int4(chunk)?;
-    let (_chunk, voxels) = count(parse_voxel, num_voxels as _)(chunk)?;
+fn parse_shape_node(input: &[u8]) -> IResult<&[u8], (u32, Node)> {
+    let (input, (node_id, _attributes, num_models)) = tuple((parse_int4, parse_dict, parse_int4))(input)?;
+    let (input, models) = count(map(pair(parse_int4, parse_dict), |(id, _attr)| id), num_models as _)(input)?;
 
-    Ok((input, (size, voxels)))
+    Ok((input, (node_id, Node::Shape { models })))
 }
 
 fn parse_color(input: &[u8]) -> IResult<&[u8], Color> {
@@ -107,60 +276,497 @@ fn parse_color(input: &[u8]) -> IResult<&[u8], Color> {
         r: rgba[0],
         g: rgba[1],
         b: rgba[2],
-        // a? no >:(
+        a: rgba[3],
     };
     Ok((input, color))
 }
 
-fn parse_palette(input: &[u8]) -> IResult<&[u8], Vec<Color>> {
-    let (input, (data, children)) = parse_chunk(b"RGBA")(input)?;
-    check_zero(data, children)?;
+fn parse_palette(data: &[u8]) -> IResult<&[u8], Vec<Color>> {
     // https://github.com/ephtracy/voxel-model/blob/8044f9eb086216f3485cdaa525a52120d72274e9/MagicaVoxel-file-format-vox.txt#L81
     // this is the line that we should use if we were to respect what the specifics says
     // but the pseudocode shown after reads only 255 colors, putting a zero (i guess) at the beginning
     // in all the files I've seen 0 is always used as thelast element, so I hope nothing is wrong?
     //let (_data, mut palette) = count(parse_color, 256)(data)?;
-    let mut palette = vec![Color::new(0, 0, 0); 256];
-    let (_data, ()) = fill(parse_color, &mut palette.as_mut_slice()[1..])(data)?;
-    Ok((input, palette))
+    let mut palette = vec![Color::with_alpha(0, 0, 0, 0); 256];
+    let (data, ()) = fill(parse_color, &mut palette.as_mut_slice()[1..])(data)?;
+    Ok((data, palette))
+}
+
+fn parse_float4(input: &[u8]) -> IResult<&[u8], f32> {
+    map(parse_int4, f32::from_bits)(input)
+}
+
+fn dict_float(dict: &Dict, key: &str) -> Option<f32> {
+    dict.get(key).and_then(|x| x.trim().parse().ok())
 }
 
-fn parse_pack(input: &[u8]) -> IResult<&[u8], u32> {
-    let (input, (data, children)) = parse_chunk(b"PACK")(input)?;
-    check_zero(data, children)?;
-    let (_data, len_models) = parse_int4(data)?;
-    Ok((input, len_models))
+/// MATL chunk (MagicaVoxel >= 0.99)
+fn parse_material(input: &[u8]) -> IResult<&[u8], (u32, Material)> {
+    let (input, (id, props)) = pair(parse_int4, parse_dict)(input)?;
+    let mut material = Material::default();
+    let weight = dict_float(&props, "_weight");
+
+    if let Some(rough) = dict_float(&props, "_rough") {
+        material.roughness = rough;
+    }
+    if let Some(ior) = dict_float(&props, "_ior") {
+        // MagicaVoxel stores the index of refraction minus one
+        material.ior = 1.0 + ior;
+    }
+    material.kind = match props.get("_type").map(|x| x.as_str()) {
+        Some("_metal") => {
+            material.metalness = dict_float(&props, "_metal").or(weight).unwrap_or(1.0);
+            MaterialKind::Metal
+        },
+        Some("_glass") => {
+            material.transparency = dict_float(&props, "_trans").or(weight).unwrap_or(1.0);
+            MaterialKind::Glass
+        },
+        Some("_emit") => {
+            let emit = dict_float(&props, "_emit").or(weight).unwrap_or(1.0);
+            let flux = dict_float(&props, "_flux").unwrap_or(0.0);
+            material.emission = (emit * (1.0 + flux)).min(Material::MAX_EMISSION);
+            MaterialKind::Emissive
+        },
+        Some("_blend") => {
+            material.metalness = dict_float(&props, "_metal").unwrap_or(0.0);
+            material.transparency = dict_float(&props, "_trans").unwrap_or(0.0);
+            material.emission = dict_float(&props, "_emit").unwrap_or(0.0);
+            MaterialKind::Diffuse
+        },
+        _ => MaterialKind::Diffuse,
+    };
+    Ok((input, (id, material)))
+}
+
+/// MATT chunk (older MagicaVoxel versions)
+fn parse_legacy_material(input: &[u8]) -> IResult<&[u8], (u32, Material)> {
+    let (mut input, (id, kind, weight, properties)) = tuple((parse_int4, parse_int4, parse_float4, parse_int4))(input)?;
+    let mut material = Material::default();
+
+    // Bits: plastic, roughness, specular, IOR, attenuation, power, glow, isTotalPower (no value)
+    let mut values = [0.0f32; 7];
+    for (bit, value) in values.iter_mut().enumerate() {
+        if properties & (1 << bit) != 0 {
+            let (rest, x) = parse_float4(input)?;
+            *value = x;
+            input = rest;
+        }
+    }
+    if properties & (1 << 1) != 0 {
+        material.roughness = values[1];
+    }
+    if properties & (1 << 3) != 0 {
+        material.ior = 1.0 + values[3];
+    }
+
+    material.kind = match kind {
+        1 => {
+            material.metalness = weight;
+            MaterialKind::Metal
+        },
+        2 => {
+            material.transparency = weight;
+            MaterialKind::Glass
+        },
+        3 => {
+            material.emission = (weight * (1.0 + values[5])).min(Material::MAX_EMISSION);
+            MaterialKind::Emissive
+        },
+        _ => MaterialKind::Diffuse,
+    };
+    Ok((input, (id, material)))
 }
 
 fn default_palette() -> Vec<Color> {
     DEFAULT_PALETTE.iter().map(|c| parse_color(&c.to_le_bytes()).unwrap().1).collect()
 }
 
-fn parse_main(input: &[u8]) -> IResult<&[u8], Vec<Scene>> {
-    let (input, (data, children)) = parse_chunk(b"MAIN")(input)?;
+/// Walks the scene graph from `node_id`, appending every visible model to `out`
+/// together with its world transform.
+fn collect_shapes(nodes: &HashMap<u32, Node>, node_id: u32, parent: Transform, depth: u32, out: &mut Vec<(u32, Transform)>) {
+    // Malformed files could contain cycles, real ones are never this deep
+    if depth > 64 {
+        log::warn!("VOX scene graph too deep, ignoring node {node_id}");
+        return;
+    }
+    match nodes.get(&node_id) {
+        Some(Node::Transform { child, transform, hidden }) => {
+            if !hidden {
+                collect_shapes(nodes, *child, parent.then(transform), depth + 1, out);
+            }
+        },
+        Some(Node::Group { children }) => {
+            for child in children {
+                collect_shapes(nodes, *child, parent, depth + 1, out);
+            }
+        },
+        Some(Node::Shape { models }) => {
+            out.extend(models.iter().map(|m| (*m, parent)));
+        },
+        None => log::warn!("VOX scene graph references missing node {node_id}"),
+    }
+}
+
+/// Places every model in the world and merges them into a single scene
+fn build_scene(models: Vec<Model>, nodes: HashMap<u32, Node>, colors: Vec<Color>, materials: Vec<Material>) -> Scene {
+    let mut placed = Vec::new();
+    if nodes.is_empty() {
+        // Files without a scene graph: every model sits at the origin
+        for model in models.iter() {
+            placed.extend(model.voxels.iter().map(|v| (v.pos.cast::<i32>().unwrap(), v.color)));
+        }
+    } else {
+        let mut shapes = Vec::new();
+        collect_shapes(&nodes, 0, Transform::IDENTITY, 0, &mut shapes);
+        for (model_id, transform) in shapes {
+            let model = match models.get(model_id as usize) {
+                Some(x) => x,
+                None => {
+                    log::warn!("VOX scene graph references missing model {model_id}");
+                    continue;
+                }
+            };
+            // Models are rotated around their center and then translated
+            let pivot = model.size.map(|x| (x / 2) as i32);
+            placed.extend(model.voxels.iter().map(|v| {
+                (transform.apply(v.pos.cast::<i32>().unwrap() - pivot), v.color)
+            }));
+        }
+    }
+
+    let min = placed.iter().fold(Vector3::new(i32::MAX, i32::MAX, i32::MAX), |a, (p, _c)| {
+        Vector3::new(a.x.min(p.x), a.y.min(p.y), a.z.min(p.z))
+    });
+    let max = placed.iter().fold(Vector3::new(i32::MIN, i32::MIN, i32::MIN), |a, (p, _c)| {
+        Vector3::new(a.x.max(p.x), a.y.max(p.y), a.z.max(p.z))
+    });
+    let (min, size) = if placed.is_empty() {
+        (Vector3::new(0, 0, 0), Vector3::new(0, 0, 0))
+    } else if nodes.is_empty() {
+        // Keep the coordinates of the file untouched
+        let size = models.iter().fold(Vector3::new(0, 0, 0), |a, m| {
+            Vector3::new(a.x.max(m.size.x), a.y.max(m.size.y), a.z.max(m.size.z))
+        });
+        (Vector3::new(0, 0, 0), size)
+    } else {
+        (min, (max - min).map(|x| x as u32 + 1))
+    };
+
+    let (voxels, issues) = place_voxels(placed.into_iter().map(|(pos, color)| {
+        let pos = (pos - min).map(|x| x as u32);
+        Voxel {
+            // invert y and z!
+            pos: Vector3::new(pos.y, pos.z, pos.x),
+            color: color as u32,
+        }
+    }));
+
+    Scene {
+        voxels,
+        colors,
+        materials,
+        grid_size: Vector3::new(size.y, size.z, size.x),
+        issues,
+    }
+}
+
+fn parse_main(input: &[u8]) -> IResult<&[u8], Scene> {
+    let (input, (data, children_len)) = parse_chunk(b"MAIN")(input)?;
     check_zero(data, data.len() as _)?;
 
-    if children == 0 {
+    if children_len == 0 {
         return Err(Err::Error(nom::error::Error::from_external_error(input, ErrorKind::NonEmpty, "No children for pack")));
     }
+    let (children, input) = input.split_at(input.len().min(children_len as usize));
+
+    // Chunks can come in any order, unknown ones are skipped by their size
+    let (rest, chunks) = many0(parse_any_chunk)(children)?;
+    if !rest.is_empty() {
+        // Truncated chunk, report why it can't be read
+        parse_any_chunk(rest)?;
+    }
+    if children.len() < children_len as usize {
+        return Err(Err::Error(nom::error::Error::from_error_kind(input, ErrorKind::Eof)));
+    }
 
-    let (input, num_models) = parse_pack(input).unwrap_or((input, 1));
-    let (input, partial_models) = count(parse_model, num_models as _)(input)?;
-    let (input, palette) =  parse_palette(input).unwrap_or_else(|_| (input, default_palette()));
+    let mut palette = None;
+    let mut nodes = HashMap::new();
+    let mut materials = Vec::new();
+    let mut models = Vec::new();
+    let mut size = None;
+    for (id, data) in chunks {
+        match id {
+            b"SIZE" => {
+                if size.is_some() {
+                    return Err(Err::Error(nom::error::Error::from_external_error(data, ErrorKind::Verify, "SIZE without XYZI")));
+                }
+                size = Some(inside(data, parse_vec3(data))?.1);
+            },
+            b"XYZI" => {
+                // Every model is a SIZE chunk followed by a XYZI one
+                let size = size.take().ok_or_else(|| {
+                    Err::Error(nom::error::Error::from_external_error(data, ErrorKind::Verify, "XYZI without SIZE"))
+                })?;
+                let (_data, voxels) = inside(data, parse_xyzi(data))?;
+                models.push(Model { size, voxels });
+            },
+            // The number of models is already known from the SIZE/XYZI pairs
+            b"PACK" => {},
+            b"RGBA" => palette = Some(inside(data, parse_palette(data))?.1),
+            b"nTRN" | b"nGRP" | b"nSHP" => {
+                let (_data, (node_id, node)) = inside(data, match id {
+                    b"nTRN" => parse_transform_node(data),
+                    b"nGRP" => parse_group_node(data),
+                    _ => parse_shape_node(data),
+                })?;
+                nodes.insert(node_id, node);
+            },
+            b"MATL" | b"MATT" => {
+                let (_data, (id, material)) = inside(data, match id {
+                    b"MATL" => parse_material(data),
+                    _ => parse_legacy_material(data),
+                })?;
+                // Material ids share the same indices as the palette
+                let id = id as usize;
+                if id < 256 {
+                    if materials.len() <= id {
+                        materials.resize(id + 1, Material::default());
+                    }
+                    materials[id] = material;
+                }
+            },
+            _ => log::debug!("Ignoring VOX chunk {}", String::from_utf8_lossy(id)),
+        }
+    }
 
-    let models = partial_models.into_iter().map(|(grid_size, voxels)| Scene {
-        voxels,
-        colors: palette.clone(),
-        grid_size,
-    }).collect();
+    if size.is_some() {
+        return Err(Err::Error(nom::error::Error::from_external_error(children, ErrorKind::Verify, "SIZE without XYZI")));
+    }
+    if models.is_empty() {
+        return Err(Err::Error(nom::error::Error::from_error_kind(children, ErrorKind::Many1)));
+    }
+
+    let palette = palette.unwrap_or_else(default_palette);
+    Ok((input, build_scene(models, nodes, palette, materials)))
+}
 
-    Ok((input, models))
+/// Id of the innermost chunk containing `offset`, used to tell where a file is broken
+pub fn chunk_at(data: &[u8], offset: usize) -> Option<[u8; 4]> {
+    // Errors at the end of the file belong to the last (truncated) chunk
+    let offset = offset.min(data.len().saturating_sub(1));
+    let mut pos = MAGIC_BYTES.len() + 4;
+    let mut end = data.len();
+    let mut found = None;
+    while pos.saturating_add(12) <= end && pos <= offset {
+        let id: [u8; 4] = data[pos..pos + 4].try_into().unwrap();
+        let data_len = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        let children_len = u32::from_le_bytes(data[pos + 8..pos + 12].try_into().unwrap()) as usize;
+        let content_end = (pos + 12).saturating_add(data_len).min(end);
+        let chunk_end = match &id {
+            // MAIN doesn't always declare the size of its children, they always last until the end
+            b"MAIN" => end,
+            _ => content_end.saturating_add(children_len).min(end),
+        };
+        if offset < chunk_end {
+            // Descend into the children (if any)
+            found = Some(id);
+            pos = content_end;
+            end = chunk_end;
+        } else {
+            pos = chunk_end;
+        }
+    }
+    found
 }
 
-pub fn parse_scene(input: &[u8]) -> IResult<&[u8], Vec<Scene>> {
-    let (input, header) = parse_header(input)?;
-    if header.version != 150 {
-        return Err(Err::Error(nom::error::Error::from_external_error(input, ErrorKind::Verify, "Invalid version")));
+pub fn parse_scene(input: &[u8]) -> IResult<&[u8], Scene> {
+    let (rest, header) = parse_header(input)?;
+    if header.version < 150 {
+        // Point the error at the version itself
+        let version = &input[MAGIC_BYTES.len()..];
+        return Err(Err::Error(nom::error::Error::from_external_error(version, ErrorKind::Verify, "Invalid version")));
+    }
+    if header.version > 200 {
+        log::warn!("VOX version {} is newer than the supported ones, unknown chunks will be skipped", header.version);
+    }
+    parse_main(rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8], content: &[u8], children: &[u8]) -> Vec<u8> {
+        let mut data = id.to_vec();
+        data.extend((content.len() as u32).to_le_bytes());
+        data.extend((children.len() as u32).to_le_bytes());
+        data.extend(content);
+        data.extend(children);
+        data
+    }
+
+    fn ints(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|x| x.to_le_bytes()).collect()
+    }
+
+    fn dict(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut data = ints(&[entries.len() as u32]);
+        for (key, value) in entries {
+            for s in [key, value] {
+                data.extend(ints(&[s.len() as u32]));
+                data.extend(s.as_bytes());
+            }
+        }
+        data
+    }
+
+    fn model(size: [u32; 3], voxels: &[[u8; 4]]) -> Vec<u8> {
+        let mut xyzi = ints(&[voxels.len() as u32]);
+        xyzi.extend(voxels.iter().flatten());
+        [chunk(b"SIZE", &ints(&size), &[]), chunk(b"XYZI", &xyzi, &[])].concat()
+    }
+
+    fn transform_node(id: u32, child: u32, frame: &[(&str, &str)]) -> Vec<u8> {
+        let content = [ints(&[id]), dict(&[]), ints(&[child, u32::MAX, 0, 1]), dict(frame)].concat();
+        chunk(b"nTRN", &content, &[])
+    }
+
+    fn group_node(id: u32, children: &[u32]) -> Vec<u8> {
+        let content = [ints(&[id]), dict(&[]), ints(&[children.len() as u32]), ints(children)].concat();
+        chunk(b"nGRP", &content, &[])
+    }
+
+    fn shape_node(id: u32, model: u32) -> Vec<u8> {
+        let content = [ints(&[id]), dict(&[]), ints(&[1, model]), dict(&[])].concat();
+        chunk(b"nSHP", &content, &[])
+    }
+
+    fn vox_file(version: u32, children: &[Vec<u8>]) -> Vec<u8> {
+        [MAGIC_BYTES.to_vec(), ints(&[version]), chunk(b"MAIN", &[], &children.concat())].concat()
+    }
+
+    /// Voxels as (scene position, color), sorted
+    fn voxels(scene: &Scene) -> Vec<([u32; 3], u32)> {
+        let mut voxels: Vec<_> = scene.voxels.iter().map(|v| ([v.pos.x, v.pos.y, v.pos.z], v.color)).collect();
+        voxels.sort();
+        voxels
+    }
+
+    /// Two models: a single voxel at the origin and a 3x1x1 bar rotated 90° around z and moved
+    fn scene_graph() -> Vec<Vec<u8>> {
+        vec![
+            model([1, 1, 1], &[[0, 0, 0, 1]]),
+            model([3, 1, 1], &[[0, 0, 0, 2], [2, 0, 0, 3]]),
+            transform_node(0, 1, &[("_t", "10 10 10")]),
+            group_node(1, &[2, 4]),
+            transform_node(2, 3, &[]),
+            shape_node(3, 0),
+            // x' = -y, y' = x
+            transform_node(4, 5, &[("_r", "17"), ("_t", "5 0 2")]),
+            shape_node(5, 1),
+        ]
+    }
+
+    #[test]
+    fn transforms_compose_like_nested_nodes() {
+        let parent = Transform { rotation: Rotation::from_byte(17).unwrap(), translation: Vector3::new(1, 2, 3) };
+        let child = Transform { rotation: Rotation::from_byte(0b0100_0100 | 2).unwrap(), translation: Vector3::new(4, -5, 6) };
+        let v = Vector3::new(7, 8, -9);
+        assert_eq!(parent.then(&child).apply(v), parent.apply(child.apply(v)));
+    }
+
+    #[test]
+    fn scene_graph_places_every_model() {
+        let (_rest, scene) = parse_scene(&vox_file(150, &scene_graph())).unwrap();
+
+        // Around its center (1, 0, 0) the bar goes from (-1, 0, 0) to (1, 0, 0), once rotated
+        // and moved it spans (5, -1, 2) to (5, 1, 2), then y = -1 becomes the scene origin
+        assert_eq!(voxels(&scene), vec![
+            ([0, 2, 5], 2),
+            ([1, 0, 0], 1),
+            ([2, 2, 5], 3),
+        ]);
+        // The grid is the bounding box of the placed models, with y and z swapped
+        assert_eq!(scene.grid_size, Vector3::new(3, 3, 6));
+    }
+
+    #[test]
+    fn hidden_nodes_are_skipped() {
+        let mut children = scene_graph();
+        children[6] = chunk(b"nTRN", &[ints(&[4]), dict(&[("_hidden", "1")]), ints(&[5, u32::MAX, 0, 1]), dict(&[])].concat(), &[]);
+        let (_rest, scene) = parse_scene(&vox_file(150, &children)).unwrap();
+        assert_eq!(voxels(&scene), vec![([0, 0, 0], 1)]);
+    }
+
+    fn located_error(data: &[u8]) -> (usize, Option<[u8; 4]>) {
+        let e = match parse_scene(data) {
+            Err(Err::Error(e) | Err::Failure(e)) => e,
+            x => panic!("Expected a parse error, got {:?}", x.map(|_| ())),
+        };
+        match crate::parser::ParseError::vox(data, e) {
+            crate::parser::ParseError::Vox { offset, chunk, .. } => (offset, chunk),
+            e => panic!("Expected a vox error, got {e}"),
+        }
+    }
+
+    #[test]
+    fn wrong_version_points_at_the_header() {
+        let data = vox_file(149, &[model([1, 1, 1], &[[0, 0, 0, 1]])]);
+        assert_eq!(located_error(&data), (4, None));
+    }
+
+    #[test]
+    fn errors_name_the_innermost_chunk() {
+        // The XYZI content starts at 8 (header) + 12 (MAIN) + 24 (SIZE) + 12 (XYZI) = 56
+        let mut xyzi = ints(&[2]);
+        xyzi.extend([0, 0, 0, 1]);
+        let missing_voxel = [chunk(b"SIZE", &ints(&[1, 1, 1]), &[]), chunk(b"XYZI", &xyzi, &[])].concat();
+        let palette = chunk(b"RGBA", &[0; 1024], &[]);
+        let data = vox_file(150, &[missing_voxel, palette]);
+        // The voxels of XYZI, not the RGBA chunk that follows
+        assert_eq!(located_error(&data), (56 + 4, Some(*b"XYZI")));
+
+        // Cut in the middle of the voxels, the chunk content is missing from its start
+        let data = vox_file(150, &[model([1, 1, 1], &[[0, 0, 0, 1], [0, 0, 0, 2]])]);
+        assert_eq!(located_error(&data[..60]), (56, Some(*b"XYZI")));
+
+        // A palette with a single color, followed by another chunk
+        let short_palette = chunk(b"RGBA", &[255; 4], &[]);
+        let data = vox_file(150, &[model([1, 1, 1], &[[0, 0, 0, 1]]), short_palette, transform_node(0, 1, &[])]);
+        // RGBA starts after the model (8 + 12 + 24 + 20) and its content after its own header
+        assert_eq!(located_error(&data), (64 + 12 + 3, Some(*b"RGBA")));
+    }
+
+    #[test]
+    fn newer_versions_with_chunks_in_any_order() {
+        let graph = scene_graph();
+        // Nodes first, then an unknown chunk, then the models
+        let mut children = graph[2..].to_vec();
+        children.push(chunk(b"NOTE", &ints(&[0]), &[]));
+        children.push(chunk(b"PACK", &ints(&[2]), &[]));
+        children.extend(graph[..2].iter().cloned());
+        let (_rest, scene) = parse_scene(&vox_file(200, &children)).unwrap();
+        let (_rest, expected) = parse_scene(&vox_file(150, &graph)).unwrap();
+        assert_eq!(voxels(&scene), voxels(&expected));
+        assert_eq!(scene.grid_size, expected.grid_size);
+    }
+
+    #[test]
+    fn models_need_their_size() {
+        let mut children = scene_graph();
+        // XYZI of the first model without its SIZE
+        children[0] = model([1, 1, 1], &[[0, 0, 0, 1]])[24..].to_vec();
+        assert!(parse_scene(&vox_file(200, &children)).is_err());
+
+        // Two SIZE chunks before the first XYZI, one of them would be lost
+        let mut children = scene_graph();
+        children.insert(0, chunk(b"SIZE", &ints(&[2, 2, 2]), &[]));
+        assert!(parse_scene(&vox_file(200, &children)).is_err());
+
+        // A SIZE without its XYZI at the end
+        let mut children = scene_graph();
+        children.push(chunk(b"SIZE", &ints(&[2, 2, 2]), &[]));
+        assert!(parse_scene(&vox_file(200, &children)).is_err());
     }
-    parse_main(input)
 }
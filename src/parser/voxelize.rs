@@ -0,0 +1,427 @@
+use std::collections::{HashMap, VecDeque};
+
+use cgmath::{Vector3, InnerSpace};
+
+use crate::color::Color;
+
+use super::{ply_model::Model, quantize::quantize, scene::{Scene, Voxel}};
+
+// Used when the mesh has no vertex colors
+const DEFAULT_COLOR: Color = Color { r: 200, g: 200, b: 200, a: 255 };
+// Leave room for the empty slot of .vox palettes
+const MAX_COLORS: usize = 255;
+// Refuse point cloud grids that could never be rendered
+const MAX_GRID_SIDE: u32 = 1 << 16;
+
+fn bounds(points: impl Iterator<Item = Vector3<f32>>) -> (Vector3<f32>, Vector3<f32>) {
+    let mut min = Vector3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY);
+    let mut max = Vector3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY);
+    for p in points {
+        min = min.zip(p, f32::min);
+        max = max.zip(p, f32::max);
+    }
+    (min, max)
+}
+
+/// Separating axis test between a triangle and a unit voxel centered at `center`
+fn triangle_overlaps_voxel(tri: &[Vector3<f32>; 3], center: Vector3<f32>) -> bool {
+    let v = tri.map(|x| x - center);
+    let edges = [v[1] - v[0], v[2] - v[1], v[0] - v[2]];
+    let normal = edges[0].cross(edges[1]);
+
+    let overlaps = |axis: Vector3<f32>| {
+        let p = v.map(|x| x.dot(axis));
+        let min = p[0].min(p[1]).min(p[2]);
+        let max = p[0].max(p[1]).max(p[2]);
+        let radius = 0.5 * (axis.x.abs() + axis.y.abs() + axis.z.abs());
+        min <= radius && max >= -radius
+    };
+
+    // The box normals are already covered by only testing voxels inside the triangle bounds
+    if !overlaps(normal) {
+        return false;
+    }
+    for edge in edges {
+        for axis in [Vector3::unit_x(), Vector3::unit_y(), Vector3::unit_z()] {
+            if !overlaps(edge.cross(axis)) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Barycentric coordinates of the point of the triangle plane nearest to `p`, clamped inside the triangle
+fn barycentric(tri: &[Vector3<f32>; 3], p: Vector3<f32>) -> [f32; 3] {
+    let e0 = tri[1] - tri[0];
+    let e1 = tri[2] - tri[0];
+    let e2 = p - tri[0];
+    let d00 = e0.dot(e0);
+    let d01 = e0.dot(e1);
+    let d11 = e1.dot(e1);
+    let d20 = e2.dot(e0);
+    let d21 = e2.dot(e1);
+    let denom = d00 * d11 - d01 * d01;
+    if denom <= f32::EPSILON * d00 * d11 {
+        // Almost degenerate, precision is gone anyway
+        return [1.0 / 3.0; 3];
+    }
+    let v = ((d11 * d20 - d01 * d21) / denom).clamp(0.0, 1.0);
+    let w = ((d00 * d21 - d01 * d20) / denom).clamp(0.0, 1.0 - v);
+    [1.0 - v - w, v, w]
+}
+
+/// Marks the surface and everything reachable from the outside, what's left unmarked is the interior.
+/// The grid is padded by one voxel on every side, the padding is always marked
+fn fill_outside(surface: &HashMap<[u32; 3], ([f32; 3], u32)>, size: Vector3<u32>) -> Vec<bool> {
+    // Pad by one voxel on every side so that the outside is always connected
+    let (w, h, d) = (size.x as usize + 2, size.y as usize + 2, size.z as usize + 2);
+    let index = |x: usize, y: usize, z: usize| x + w * (y + h * z);
+    let mut outside = vec![false; w * h * d];
+    for pos in surface.keys() {
+        // Surface voxels are never outside, mark them as visited
+        outside[index(pos[0] as usize + 1, pos[1] as usize + 1, pos[2] as usize + 1)] = true;
+    }
+    let mut stack = vec![(0usize, 0usize, 0usize)];
+    outside[0] = true;
+    while let Some((x, y, z)) = stack.pop() {
+        let neighbours = [
+            (x.wrapping_sub(1), y, z), (x + 1, y, z),
+            (x, y.wrapping_sub(1), z), (x, y + 1, z),
+            (x, y, z.wrapping_sub(1)), (x, y, z + 1),
+        ];
+        for (nx, ny, nz) in neighbours {
+            if nx >= w || ny >= h || nz >= d || outside[index(nx, ny, nz)] {
+                continue;
+            }
+            outside[index(nx, ny, nz)] = true;
+            stack.push((nx, ny, nz));
+        }
+    }
+    outside
+}
+
+/// Converts a triangle mesh to voxels, `resolution` is the size of the longest side in voxels.
+/// When `solid` is set closed volumes are filled, otherwise only the surface is kept.
+pub fn voxelize(mesh: &Model, resolution: u32, solid: bool) -> anyhow::Result<Scene> {
+    if resolution == 0 {
+        anyhow::bail!("Resolution must be positive");
+    }
+    let triangles: Vec<[u32; 3]> = mesh.indices.chunks_exact(3).map(|x| [x[0], x[1], x[2]]).collect();
+    if triangles.is_empty() {
+        anyhow::bail!("Mesh has no faces");
+    }
+
+    let positions: Vec<Vector3<f32>> = mesh.vertices.iter().map(|x| Vector3::from(x.position)).collect();
+    let (min, max) = bounds(triangles.iter().flatten().map(|i| positions[*i as usize]));
+    let extent = max - min;
+    let longest = extent.x.max(extent.y).max(extent.z);
+    if !longest.is_finite() || longest <= 0.0 {
+        anyhow::bail!("Mesh has no volume");
+    }
+    let scale = resolution as f32 / longest;
+    let grid_size = (extent * scale).map(|x| (x.ceil() as u32).clamp(1, resolution));
+
+    // Sum of the colors that hit every voxel and how many times it has been hit
+    let mut surface: HashMap<[u32; 3], ([f32; 3], u32)> = HashMap::new();
+    for tri in triangles.iter() {
+        let verts = tri.map(|i| (positions[i as usize] - min) * scale);
+        if (verts[1] - verts[0]).cross(verts[2] - verts[0]).magnitude2() == 0.0 {
+            // Degenerate, it has no surface to voxelize
+            continue;
+        }
+        let tri_min = verts[0].zip(verts[1], f32::min).zip(verts[2], f32::min);
+        let tri_max = verts[0].zip(verts[1], f32::max).zip(verts[2], f32::max);
+        // Faces lying on the far side of the bounds still belong to the last voxel
+        let lo = tri_min.zip(grid_size, |x, s| (x.floor().max(0.0) as u32).min(s - 1));
+        let hi = tri_max.zip(grid_size, |x, s| (x.floor() as u32).min(s - 1));
+
+        for z in lo.z..=hi.z {
+            for y in lo.y..=hi.y {
+                for x in lo.x..=hi.x {
+                    let center = Vector3::new(x as f32 + 0.5, y as f32 + 0.5, z as f32 + 0.5);
+                    if !triangle_overlaps_voxel(&verts, center) {
+                        continue;
+                    }
+                    let color = match &mesh.colors {
+                        Some(colors) => {
+                            let weights = barycentric(&verts, center);
+                            let mut color = Vector3::new(0.0, 0.0, 0.0);
+                            for (i, weight) in tri.iter().zip(weights) {
+                                let c = colors[*i as usize];
+                                color += Vector3::new(c.r as f32, c.g as f32, c.b as f32) * weight;
+                            }
+                            color
+                        },
+                        None => Vector3::new(0.0, 0.0, 0.0),
+                    };
+                    let entry = surface.entry([x, y, z]).or_insert(([0.0; 3], 0));
+                    entry.0 = (Vector3::from(entry.0) + color).into();
+                    entry.1 += 1;
+                }
+            }
+        }
+    }
+
+    let mut positions = Vec::with_capacity(surface.len());
+    let mut colors = Vec::with_capacity(surface.len());
+    for (pos, (sum, count)) in surface.iter() {
+        let avg = Vector3::from(*sum) / *count as f32;
+        positions.push(*pos);
+        colors.push(Color::new(avg.x.round() as u8, avg.y.round() as u8, avg.z.round() as u8));
+    }
+
+    if solid {
+        let mut reached = fill_outside(&surface, grid_size);
+        let (w, h) = (grid_size.x as usize + 2, grid_size.y as usize + 2);
+        // -1 wraps around to the padding at 0
+        let index = |p: [u32; 3]| {
+            let [x, y, z] = p.map(|x| x.wrapping_add(1) as usize);
+            x + w * (y + h * z)
+        };
+        // Grow the surface inwards one layer at a time, so that every interior voxel takes
+        // the color of the nearest surface voxel. Sorted to break ties the same way every time
+        let mut queue: Vec<_> = positions.iter().copied().zip(colors.iter().copied()).collect();
+        queue.sort_unstable_by_key(|(pos, _color)| [pos[2], pos[1], pos[0]]);
+        let mut queue = VecDeque::from(queue);
+        while let Some(([x, y, z], color)) = queue.pop_front() {
+            // The padding is marked, so the neighbours of a voxel in the grid never leave it
+            let neighbours = [
+                [x.wrapping_sub(1), y, z], [x + 1, y, z],
+                [x, y.wrapping_sub(1), z], [x, y + 1, z],
+                [x, y, z.wrapping_sub(1)], [x, y, z + 1],
+            ];
+            for pos in neighbours {
+                if reached[index(pos)] {
+                    continue;
+                }
+                reached[index(pos)] = true;
+                positions.push(pos);
+                colors.push(color);
+                queue.push_back((pos, color));
+            }
+        }
+    }
+
+    log::info!("Voxelized {} triangles into {} voxels", triangles.len(), positions.len());
+    let colors = mesh.colors.as_ref().map(|_| colors);
+    Ok(build_scene(positions, colors, grid_size))
+}
+
+/// Bins a point cloud (a PLY without faces) into voxels, every voxel takes the average color of its points.
+/// `voxel_size` is in the units of the file, by default it's chosen to fit `resolution` voxels on the longest side.
+pub fn bin_points(cloud: &Model, resolution: u32, voxel_size: Option<f32>) -> anyhow::Result<Scene> {
+    let points: Vec<(usize, Vector3<f32>)> = cloud.vertices.iter()
+        .map(|x| Vector3::from(x.position))
+        .enumerate()
+        .filter(|(_i, p)| p.x.is_finite() && p.y.is_finite() && p.z.is_finite())
+        .collect();
+    if points.is_empty() {
+        anyhow::bail!("Point cloud is empty");
+    }
+
+    let (min, max) = bounds(points.iter().map(|(_i, p)| *p));
+    let extent = max - min;
+    let voxel_size = match voxel_size {
+        Some(x) if x > 0.0 && x.is_finite() => x,
+        Some(_) => anyhow::bail!("Voxel size must be positive"),
+        None if resolution == 0 => anyhow::bail!("Resolution must be positive"),
+        // A single point (or a flat cloud) still makes one voxel
+        None => extent.x.max(extent.y).max(extent.z).max(f32::MIN_POSITIVE) / resolution as f32,
+    };
+    let cells = extent / voxel_size;
+    if cells.x.max(cells.y).max(cells.z) >= MAX_GRID_SIDE as f32 {
+        anyhow::bail!("Voxel size too small, the grid would be {:.0}x{:.0}x{:.0}", cells.x, cells.y, cells.z);
+    }
+    let grid_size = cells.map(|x| x.ceil().max(1.0) as u32);
+
+    // Sum of the colors of the points in every voxel, and how many they are
+    let mut cells: HashMap<[u32; 3], ([u64; 3], u32)> = HashMap::new();
+    for (i, p) in points.iter() {
+        let pos = ((p - min) / voxel_size).zip(grid_size, |x, s| (x as u32).min(s - 1));
+        let color = cloud.colors.as_ref().map(|x| x[*i]).unwrap_or(DEFAULT_COLOR);
+        let entry = cells.entry(pos.into()).or_insert(([0; 3], 0));
+        entry.0[0] += color.r as u64;
+        entry.0[1] += color.g as u64;
+        entry.0[2] += color.b as u64;
+        entry.1 += 1;
+    }
+
+    let mut positions = Vec::with_capacity(cells.len());
+    let mut colors = Vec::with_capacity(cells.len());
+    for (pos, (sum, count)) in cells {
+        let c = sum.map(|x| ((x + count as u64 / 2) / count as u64) as u8);
+        positions.push(pos);
+        colors.push(Color::new(c[0], c[1], c[2]));
+    }
+
+    log::info!("Binned {} points into {} voxels", points.len(), positions.len());
+    let colors = cloud.colors.as_ref().map(|_| colors);
+    Ok(build_scene(positions, colors, grid_size))
+}
+
+/// Quantizes the voxel colors into a palette, without colors everything uses the default one
+fn build_scene(positions: Vec<[u32; 3]>, colors: Option<Vec<Color>>, grid_size: Vector3<u32>) -> Scene {
+    let (palette, indices) = match colors {
+        Some(colors) => quantize(&colors, MAX_COLORS),
+        None => (vec![DEFAULT_COLOR], vec![0; positions.len()]),
+    };
+    log::debug!("Quantized to {} colors", palette.len());
+
+    let voxels = positions.into_iter().zip(indices)
+        .map(|(pos, color)| Voxel { pos: Vector3::from(pos), color })
+        .collect();
+
+    Scene {
+        voxels,
+        colors: palette,
+        materials: Vec::new(),
+        grid_size,
+        issues: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::ModelVertex;
+
+    use super::*;
+
+    /// Unit cube, the vertices at x = 0 are red and the ones at x = 1 blue
+    fn cube(colored: bool) -> Model {
+        let positions: Vec<[f32; 3]> = (0..8).map(|i| [i & 1, (i >> 1) & 1, (i >> 2) & 1].map(|x| x as f32)).collect();
+        let colors = positions.iter()
+            .map(|p| if p[0] == 0.0 { Color::new(255, 0, 0) } else { Color::new(0, 0, 255) })
+            .collect();
+        let quads = [[0, 2, 3, 1], [4, 5, 7, 6], [0, 1, 5, 4], [2, 6, 7, 3], [0, 4, 6, 2], [1, 3, 7, 5]];
+        Model {
+            vertices: positions.into_iter().map(|position| ModelVertex { position, normal: [0.0; 3] }).collect(),
+            indices: quads.iter().flat_map(|[a, b, c, d]| [*a, *b, *c, *a, *c, *d]).collect(),
+            colors: colored.then_some(colors),
+            uvs: None,
+        }
+    }
+
+    fn color_at(scene: &Scene, pos: [u32; 3]) -> Color {
+        let voxel = scene.voxels.iter().find(|v| v.pos == Vector3::from(pos)).unwrap();
+        scene.colors[voxel.color as usize]
+    }
+
+    #[test]
+    fn surface() {
+        let scene = voxelize(&cube(false), 4, false).unwrap();
+        assert_eq!(scene.grid_size, Vector3::new(4, 4, 4));
+        // Everything but the 2x2x2 core
+        assert_eq!(scene.voxels.len(), 64 - 8);
+        assert!(scene.voxels.iter().all(|v| [v.pos.x, v.pos.y, v.pos.z].iter().any(|x| *x == 0 || *x == 3)));
+        assert_eq!(scene.colors, [DEFAULT_COLOR]);
+    }
+
+    #[test]
+    fn solid() {
+        let scene = voxelize(&cube(false), 4, true).unwrap();
+        assert_eq!(scene.voxels.len(), 64);
+    }
+
+    #[test]
+    fn interior_takes_the_nearest_surface_color() {
+        let scene = voxelize(&cube(true), 8, true).unwrap();
+        assert_eq!(scene.voxels.len(), 512);
+        let red = color_at(&scene, [1, 4, 4]);
+        let blue = color_at(&scene, [6, 4, 4]);
+        assert!(red.r > red.b, "{red:?}");
+        assert!(blue.b > blue.r, "{blue:?}");
+    }
+
+    #[test]
+    fn invalid_meshes() {
+        assert!(voxelize(&cube(false), 0, false).is_err());
+        let mut flat = cube(false);
+        flat.vertices.iter_mut().for_each(|v| v.position = [0.0; 3]);
+        assert!(voxelize(&flat, 4, false).is_err());
+        flat.indices.clear();
+        assert!(voxelize(&flat, 4, false).is_err());
+    }
+
+    fn cloud(points: &[[f32; 3]], colors: Option<&[Color]>) -> Model {
+        Model {
+            vertices: points.iter().map(|position| ModelVertex { position: *position, normal: [0.0; 3] }).collect(),
+            indices: Vec::new(),
+            colors: colors.map(|x| x.to_vec()),
+            uvs: None,
+        }
+    }
+
+    /// Sorted positions with their colors
+    fn voxels(scene: &Scene) -> Vec<([u32; 3], Color)> {
+        let mut voxels: Vec<_> = scene.voxels.iter().map(|v| (v.pos.into(), scene.colors[v.color as usize])).collect();
+        voxels.sort_by_key(|(pos, _color)| *pos);
+        voxels
+    }
+
+    /// Two points in the first voxel and one 3 units away along x
+    fn three_points() -> Model {
+        let colors = [Color::new(200, 0, 0), Color::new(100, 51, 0), Color::new(0, 255, 0)];
+        cloud(&[[0.0, 0.0, 0.0], [0.5, 0.5, 0.5], [3.0, 0.0, 0.0]], Some(&colors))
+    }
+
+    #[test]
+    fn points_average_their_colors() {
+        let scene = bin_points(&three_points(), 64, Some(1.0)).unwrap();
+        assert_eq!(scene.grid_size, Vector3::new(3, 1, 1));
+        // The last point is on the far boundary, it stays in the grid
+        assert_eq!(voxels(&scene), [([0, 0, 0], Color::new(150, 26, 0)), ([2, 0, 0], Color::new(0, 255, 0))]);
+    }
+
+    #[test]
+    fn voxel_size_overrides_resolution() {
+        // 6 voxels along the longest side, 0.5 units each
+        let scene = bin_points(&three_points(), 6, None).unwrap();
+        assert_eq!(scene.grid_size, Vector3::new(6, 1, 1));
+        let positions: Vec<_> = voxels(&scene).into_iter().map(|(pos, _color)| pos).collect();
+        assert_eq!(positions, [[0, 0, 0], [1, 0, 0], [5, 0, 0]]);
+
+        let scene = bin_points(&three_points(), 6, Some(3.0)).unwrap();
+        assert_eq!(scene.grid_size, Vector3::new(1, 1, 1));
+        assert_eq!(scene.voxels.len(), 1);
+    }
+
+    #[test]
+    fn degenerate_clouds() {
+        for points in [&[[1.0, 2.0, 3.0]][..], &[[1.0, 2.0, 3.0]; 4]] {
+            let scene = bin_points(&cloud(points, None), 64, None).unwrap();
+            assert_eq!(scene.grid_size, Vector3::new(1, 1, 1));
+            assert_eq!(voxels(&scene), [([0, 0, 0], DEFAULT_COLOR)]);
+        }
+        // Flat along z, it's one voxel thick
+        let flat = cloud(&[[0.0, 0.0, 5.0], [4.0, 0.0, 5.0], [0.0, 4.0, 5.0]], None);
+        let scene = bin_points(&flat, 4, None).unwrap();
+        assert_eq!(scene.grid_size, Vector3::new(4, 4, 1));
+        assert_eq!(scene.voxels.len(), 3);
+    }
+
+    #[test]
+    fn non_finite_points_are_dropped() {
+        let colors = [Color::new(255, 0, 0), Color::new(0, 255, 0), Color::new(0, 0, 255), Color::new(0, 0, 255)];
+        let points = [[0.0, 0.0, 0.0], [f32::NAN, 0.0, 0.0], [2.0, 0.0, f32::INFINITY], [2.0, 0.0, 0.0]];
+        let scene = bin_points(&cloud(&points, Some(&colors)), 2, None).unwrap();
+        // The bounds come from the finite points only, and colors stay with their points
+        assert_eq!(scene.grid_size, Vector3::new(2, 1, 1));
+        assert_eq!(voxels(&scene), [([0, 0, 0], Color::new(255, 0, 0)), ([1, 0, 0], Color::new(0, 0, 255))]);
+
+        let e = bin_points(&cloud(&[[f32::NAN; 3]], None), 2, None).unwrap_err();
+        assert_eq!(e.to_string(), "Point cloud is empty");
+    }
+
+    #[test]
+    fn invalid_point_grids() {
+        let points = three_points();
+        let e = bin_points(&points, 64, Some(3.0 / MAX_GRID_SIDE as f32)).unwrap_err();
+        assert!(e.to_string().starts_with("Voxel size too small"), "{e}");
+        assert!(bin_points(&points, 64, Some(3.0 / (MAX_GRID_SIDE - 1) as f32)).is_ok());
+        for size in [0.0, -1.0, f32::NAN] {
+            assert!(bin_points(&points, 64, Some(size)).is_err(), "{size}");
+        }
+        assert!(bin_points(&points, 0, None).is_err());
+    }
+}
@@ -1,17 +1,58 @@
-use wgpu::{RenderPassDescriptor, RenderPassDepthStencilAttachment};
+use wgpu::{RenderPassDescriptor, RenderPassDepthStencilAttachment, TextureView};
 
 use crate::app::App;
 
+/// How the scene is drawn, when rasterizing translucent voxels are always sorted instances
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RenderMode {
+    /// One cube instance per voxel
+    #[default]
+    Instanced,
+    /// A single mesh where coplanar faces with the same color are merged
+    Greedy,
+    /// Rays marched through a 3D texture of the scene, translucent voxels included
+    Raymarch,
+}
 
+impl RenderMode {
+    pub fn next(self) -> Self {
+        match self {
+            RenderMode::Instanced => RenderMode::Greedy,
+            RenderMode::Greedy => RenderMode::Raymarch,
+            RenderMode::Raymarch => RenderMode::Instanced,
+        }
+    }
+}
 
 pub fn render(app: &mut App) {
     //log::info!("Handling Redraw Request");
 
-    let (surface_state, rs) = match (&app.surface_state, &app.render_state) {
-        (Some(x), Some(y)) => (x, y),
+    let surface_state = match (&app.surface_state, &app.render_state) {
+        (Some(x), Some(_)) => x,
         _ => return,
     };
 
+    let frame = surface_state
+        .surface
+        .get_current_texture()
+        .expect("Failed to acquire next swap chain texture");
+
+    let view = frame
+        .texture
+        .create_view(&wgpu::TextureViewDescriptor::default());
+
+    draw(app, &view);
+    frame.present();
+    surface_state.window.request_redraw();
+}
+
+/// Draws the scene with the current render mode into `view`, the window's swapchain or an offscreen texture.
+/// The target must have the size of the depth texture and the format the render state was created with.
+pub fn draw(app: &App, view: &TextureView) {
+    let Some(rs) = &app.render_state else {
+        return;
+    };
+
     // Update camera uniforms
     rs.queue.write_buffer(
         &rs.camera_buffer,
@@ -26,29 +67,46 @@ pub fn render(app: &mut App) {
         bytemuck::cast_slice(&[rs.pos_info_uniform]),
     );
 
-    let frame = surface_state
-        .surface
-        .get_current_texture()
-        .expect("Failed to acquire next swap chain texture");
-
-    let view = frame
-        .texture
-        .create_view(&wgpu::TextureViewDescriptor::default());
-
     let mut encoder =
         rs.device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                 label: None,
             });
+    // Depth seen from the light first, the main pass samples it for shadows
+    {
+        let mut shadow_pass =
+            encoder.begin_render_pass(&RenderPassDescriptor {
+                label: Some("shadow_pass"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+                    view: &rs.shadow_texture.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: wgpu::StoreOp::Store,
+                    }),
+                    stencil_ops: None,
+                }),
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+        // Translucent voxels are not in the mesh, glass doesn't cast shadows
+        if rs.mesh_index_count > 0 {
+            shadow_pass.set_pipeline(&rs.shadow_pipeline);
+            shadow_pass.set_bind_group(0, &rs.camera_bind_group, &[]);
+            shadow_pass.set_vertex_buffer(0, rs.mesh_vertex_buffer.slice(..));
+            shadow_pass.set_index_buffer(rs.mesh_index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+            shadow_pass.draw_indexed(0..rs.mesh_index_count, 0, 0..1);
+        }
+    }
     {
         let mut rpass =
             encoder.begin_render_pass(&RenderPassDescriptor {
                 label: None,
                 color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
+                    view,
                     resolve_target: None,
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        load: wgpu::LoadOp::Clear(app.clear_color),
                         store: wgpu::StoreOp::Store,
                     },
                 })],
@@ -63,19 +121,39 @@ pub fn render(app: &mut App) {
                 timestamp_writes: None,
                 occlusion_query_set: None,
             });
-        rpass.set_pipeline(&rs.render_pipeline);
         rpass.set_bind_group(0, &rs.camera_bind_group, &[]);
         rpass.set_bind_group(1, &rs.texture_bind_group, &[]);
         rpass.set_bind_group(2, &rs.pos_info_bind_group, &[]);
-        rpass.set_vertex_buffer(0, rs.vertex_buffer.slice(..));
-        rpass.set_vertex_buffer(1, rs.instance_buffer.slice(..));
-        rpass.set_index_buffer(rs.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
 
         let index_count = rs.model.indices.len() as _;
-        let instance_count = rs.instance_count;
-        rpass.draw_indexed(0..index_count, 0, 0..instance_count);
+        match app.render_mode {
+            RenderMode::Instanced => {
+                rpass.set_pipeline(&rs.render_pipeline);
+                rpass.set_vertex_buffer(0, rs.vertex_buffer.slice(..));
+                rpass.set_vertex_buffer(1, rs.instance_buffer.slice(..));
+                rpass.set_index_buffer(rs.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                rpass.draw_indexed(0..index_count, 0, 0..rs.instance_count);
+            },
+            RenderMode::Greedy => {
+                rpass.set_pipeline(&rs.mesh_pipeline);
+                rpass.set_vertex_buffer(0, rs.mesh_vertex_buffer.slice(..));
+                rpass.set_index_buffer(rs.mesh_index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                rpass.draw_indexed(0..rs.mesh_index_count, 0, 0..1);
+            },
+            RenderMode::Raymarch => {
+                rpass.set_pipeline(&rs.raymarch_pipeline);
+                rpass.set_bind_group(3, &rs.voxel_bind_group, &[]);
+                rpass.draw(0..3, 0..1);
+            },
+        }
+
+        if app.render_mode != RenderMode::Raymarch && !rs.transparent_instances.is_empty() {
+            rpass.set_pipeline(&rs.transparent_pipeline);
+            rpass.set_vertex_buffer(0, rs.vertex_buffer.slice(..));
+            rpass.set_index_buffer(rs.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+            rpass.set_vertex_buffer(1, rs.transparent_buffer.slice(..));
+            rpass.draw_indexed(0..index_count, 0, 0..rs.transparent_instances.len() as _);
+        }
     }
     rs.queue.submit(Some(encoder.finish()));
-    frame.present();
-    surface_state.window.request_redraw();
 }
@@ -2,6 +2,8 @@
 
 struct Camera {
     view_proj: mat4x4<f32>,
+    inv_view_proj: mat4x4<f32>,
+    light_view_proj: mat4x4<f32>,
 }
 @group(0) @binding(0)
 var<uniform> camera: Camera;
@@ -13,6 +15,9 @@ struct VertexInput {
 struct InstanceInput {
     @location(5) pos: vec3<f32>,
     @location(6) color: u32,
+    @location(7) faces: u32,
+    // 2 bits of ambient occlusion for every corner of every face
+    @location(8) ao: vec2<u32>,
 }
 
 struct VertexOutput {
@@ -20,6 +25,8 @@ struct VertexOutput {
     @location(0) color: u32,
     @location(1) v_pos: vec3<f32>,
     @location(2) v_norm: vec3<f32>,
+    // From 0 (in a crease) to 3 (nothing around)
+    @location(3) ao: f32,
 }
 
 @vertex
@@ -29,12 +36,54 @@ fn vs_main(
 ) -> VertexOutput {
     var out: VertexOutput;
     out.clip_position = camera.view_proj * vec4<f32>(model.position + instance.pos, 1.0);
+
+    // Same order as the face bits: +x, -x, +y, -y, +z, -z
+    var n = model.normal;
+    var axis = select(select(2u, 1u, abs(n.y) > abs(n.z)), 0u, abs(n.x) > max(abs(n.y), abs(n.z)));
+    var direction = axis * 2u + select(0u, 1u, n[axis] < 0.0);
+    if (instance.faces & (1u << direction)) == 0u {
+        // Hidden face: every vertex ends up outside of the clip volume, so the triangle is dropped
+        out.clip_position = vec4(0.0, 0.0, 2.0, 1.0);
+    }
+    // Corners are numbered by their side of the cube along the other two axes
+    var a1 = select(0u, 1u, axis == 0u);
+    var a2 = select(2u, 1u, axis == 2u);
+    var p = model.position;
+    var corner = select(0u, 1u, p[a1] > 0.0) | select(0u, 2u, p[a2] > 0.0);
+    var bit = 2u * (4u * direction + corner);
+    var word = select(instance.ao.x, instance.ao.y, bit >= 32u);
+    out.ao = f32((word >> (bit % 32u)) & 3u);
     out.color = instance.color;
     out.v_pos = model.position + instance.pos;
     out.v_norm = model.normal;
     return out;
 }
 
+struct MeshInput {
+    @location(0) position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+    @location(2) ao: f32,
+    @location(6) color: u32,
+}
+
+// Greedy meshing: positions are already in world space and every vertex carries its color
+@vertex
+fn vs_mesh(model: MeshInput) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = camera.view_proj * vec4<f32>(model.position, 1.0);
+    out.color = model.color;
+    out.v_pos = model.position;
+    out.v_norm = model.normal;
+    out.ao = model.ao;
+    return out;
+}
+
+// Shadow map, only the depth seen from the light is written
+@vertex
+fn vs_shadow(model: MeshInput) -> @builtin(position) vec4<f32> {
+    return camera.light_view_proj * vec4<f32>(model.position, 1.0);
+}
+
 // Fragment shader
 
 struct PosInfo {
@@ -43,26 +92,249 @@ struct PosInfo {
 }
 
 @group(1) @binding(0) var t_color: texture_2d<f32>;
+// roughness, metalness, emission / 4, transparency
+// the bottom half has the kind and (ior - 1) / 2 of the same entry
+@group(1) @binding(1) var t_material: texture_2d<f32>;
+@group(1) @binding(2) var t_shadow: texture_depth_2d;
+@group(1) @binding(3) var s_shadow: sampler_comparison;
 @group(2) @binding(0) var<uniform> pos: PosInfo;
 
+// Same values as `MaterialKind`
+const KIND_METAL: u32 = 1u;
+const KIND_GLASS: u32 = 2u;
+const KIND_EMISSIVE: u32 = 3u;
+
+/// Schlick's approximation of the Fresnel reflectance, `f0` is the one when facing the surface
+fn fresnel(f0: vec4<f32>, cos_theta: f32) -> vec4<f32> {
+    return f0 + (1.0 - f0) * pow(1.0 - clamp(cos_theta, 0.0, 1.0), 5.0);
+}
+
+/// How much of the light reaches `v_pos`: 0 in shadow, 1 fully lit
+fn light_visibility(v_pos: vec3<f32>, v_norm: vec3<f32>) -> f32 {
+    // Pushing the point out along the normal keeps faces from shadowing themselves. It has to cover a shadow
+    // map texel and its PCF neighbours, whose size grows with the distance from the light: the x row of the
+    // matrix is the view x axis scaled by the projection, and clip w is the distance along the light direction
+    var light = camera.light_view_proj;
+    var texels_per_unit = length(vec3(light[0].x, light[1].x, light[2].x)) * 0.5 * f32(textureDimensions(t_shadow).x);
+    var depth = (light * vec4(v_pos, 1.0)).w;
+    var clip = light * vec4(v_pos + v_norm * 2.0 * depth / texels_per_unit, 1.0);
+    if clip.w <= 0.0 {
+        return 1.0;
+    }
+    var ndc = clip.xyz / clip.w;
+    var uv = vec2(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+    if any(uv < vec2(0.0)) || any(uv > vec2(1.0)) || ndc.z > 1.0 {
+        return 1.0;
+    }
+
+    // 3x3 PCF, softens the edges of the shadow map texels
+    var texel = 1.0 / vec2<f32>(textureDimensions(t_shadow));
+    var lit = 0.0;
+    for (var y = -1; y <= 1; y++) {
+        for (var x = -1; x <= 1; x++) {
+            lit += textureSampleCompareLevel(t_shadow, s_shadow, uv + vec2(f32(x), f32(y)) * texel, ndc.z);
+        }
+    }
+    return lit / 9.0;
+}
+
+/// Blinn-Phong with the palette entry at `coord`, the result has premultiplied alpha.
+/// `ao` is the ambient occlusion level, from 0 (in a crease) to 3 (nothing around)
+fn shade(coord: vec2<u32>, v_pos: vec3<f32>, v_norm: vec3<f32>, ao: f32) -> vec4<f32> {
+    // Look mum, no sampler!
+    var color = textureLoad(t_color, coord, 0);
+    var material = textureLoad(t_material, coord, 0);
+    var roughness = material.r;
+    var metalness = material.g;
+    var emission = material.b * 4.0;
+    var transparency = material.a;
+    var optics = textureLoad(t_material, vec2(coord.x, coord.y + textureDimensions(t_color).y), 0);
+    var kind = u32(round(optics.r * 255.0));
+    var ior = 1.0 + optics.g * 2.0;
+
+    var light_dir = normalize(pos.light - v_pos);
+    var eye_dir = normalize(pos.eye - v_pos);
+    var diffuse = max(dot(light_dir, v_norm), 0.0);
+
+    var half_way = normalize(light_dir + eye_dir);
+    var shininess = mix(200.0, 2.0, roughness);
+    var specular = pow(max(dot(half_way, v_norm), 0.0), shininess);
+    var visibility = light_visibility(v_pos, v_norm);
+    diffuse *= visibility;
+    specular *= visibility;
+
+    // Metals have no diffuse component and tint their reflections
+    var specular_map = mix(vec4(1.0), color, metalness);
+    switch kind {
+        case KIND_METAL: {
+            // Tinted when facing the surface, white at grazing angles
+            specular_map = mix(vec4(1.0), fresnel(color, dot(half_way, eye_dir)), metalness);
+        }
+        case KIND_GLASS: {
+            // Reflects more and lets less light through at grazing angles
+            var f0 = pow((ior - 1.0) / (ior + 1.0), 2.0);
+            transparency *= 1.0 - fresnel(vec4(f0), dot(eye_dir, v_norm)).r;
+        }
+        case KIND_EMISSIVE: {
+            // Light sources glow evenly, without a side in the dark
+            diffuse = mix(diffuse, 1.0, min(emission, 1.0));
+        }
+        default: {}
+    }
+
+    var diffuse_map = color * (1.0 - metalness);
+    var ambient_comp = 0.3 * color * (1.0 - 0.5 * metalness) * mix(0.4, 1.0, ao / 3.0);
+    var emission_comp = emission * color;
+
+    // Premultiplied alpha: glass lets the light through but still reflects the highlights
+    var alpha = color.a * (1.0 - transparency);
+    var lit = (ambient_comp + diffuse * diffuse_map) * alpha + specular * specular_map + emission_comp;
+    return vec4(lit.rgb, alpha);
+}
+
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     var y = in.color >> 16u;
     var x = in.color & 0xFFFFu;
+    return shade(vec2(x, y), in.v_pos, in.v_norm, in.ao);
+}
 
-    // Look mum, no sampler!
-    var color = textureLoad(t_color, vec2(x, y), 0);
+// Ray marching
 
-    var diffuse_map = color * 1.0;
-    var specular_map = vec4(1.0);
-    var ambient_comp = 0.3 * color;
+// Palette index + 1 of every voxel, 0 is empty
+@group(3) @binding(0) var t_voxels: texture_3d<u32>;
 
-    var light_dir = normalize(pos.light - in.v_pos);
-    var eye_dir = normalize(pos.eye - in.v_pos);
-    var diffuse = max(dot(light_dir, in.v_norm), 0.0);
+struct FullscreenOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) ndc: vec2<f32>,
+}
 
-    var half_way = normalize(light_dir + eye_dir);
-    var specular = pow(max(dot(half_way, in.v_norm), 0.0), 100.0);
+@vertex
+fn vs_fullscreen(@builtin(vertex_index) index: u32) -> FullscreenOutput {
+    // A single triangle covering the whole screen
+    var uv = vec2(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: FullscreenOutput;
+    out.ndc = uv * 2.0 - 1.0;
+    out.clip_position = vec4(out.ndc, 0.0, 1.0);
+    return out;
+}
+
+/// Whether `cell` holds a voxel that blocks the light
+fn is_opaque(cell: vec3<i32>) -> bool {
+    if any(cell < vec3(0)) || any(cell >= vec3<i32>(textureDimensions(t_voxels))) {
+        return false;
+    }
+    var value = textureLoad(t_voxels, cell, 0).r;
+    if value == 0u {
+        return false;
+    }
+    var edge = textureDimensions(t_color).x;
+    var coord = vec2((value - 1u) % edge, (value - 1u) / edge);
+    return textureLoad(t_color, coord, 0).a >= 1.0 && textureLoad(t_material, coord, 0).a == 0.0;
+}
+
+/// Same ambient occlusion as the rasterized faces, from the voxels in front of the face of `cell`
+/// pointing towards `normal`, interpolated between the corners at `hit` (grid space)
+fn voxel_occlusion(cell: vec3<i32>, normal: vec3<f32>, hit: vec3<f32>) -> f32 {
+    var axis = select(select(2u, 1u, normal.y != 0.0), 0u, normal.x != 0.0);
+    var a1 = select(0u, 1u, axis == 0u);
+    var a2 = select(2u, 1u, axis == 2u);
+    var e1 = select(vec3(0), vec3(1), vec3(a1) == vec3(0u, 1u, 2u));
+    var e2 = select(vec3(0), vec3(1), vec3(a2) == vec3(0u, 1u, 2u));
+    var front = cell + vec3<i32>(normal);
+
+    var levels: array<f32, 4>;
+    for (var corner = 0; corner < 4; corner++) {
+        var s1 = select(-1, 1, (corner & 1) != 0);
+        var s2 = select(-1, 1, (corner & 2) != 0);
+        var side1 = is_opaque(front + e1 * s1);
+        var side2 = is_opaque(front + e2 * s2);
+        var diagonal = is_opaque(front + e1 * s1 + e2 * s2);
+        if side1 && side2 {
+            levels[corner] = 0.0;
+        } else {
+            levels[corner] = 3.0 - f32(side1) - f32(side2) - f32(diagonal);
+        }
+    }
+    var f = clamp(hit - vec3<f32>(cell), vec3(0.0), vec3(1.0));
+    return mix(mix(levels[0], levels[1], f[a1]), mix(levels[2], levels[3], f[a1]), f[a2]);
+}
+
+@fragment
+fn fs_raymarch(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    // Two points on the ray of this pixel, both right in front of the camera
+    var near = camera.inv_view_proj * vec4(in.ndc, 0.0, 1.0);
+    var far = camera.inv_view_proj * vec4(in.ndc, 0.1, 1.0);
+    var dir = normalize(far.xyz / far.w - near.xyz / near.w);
+    dir = select(dir, vec3(1e-6), abs(dir) < vec3(1e-6));
+    // Voxel p covers [p - 0.5, p + 0.5], in grid space cell p covers [p, p + 1]
+    var origin = near.xyz / near.w + 0.5;
+    var size = vec3<i32>(textureDimensions(t_voxels));
+    var edge = textureDimensions(t_color).x;
+
+    // Where the ray enters and leaves the grid
+    var inv = 1.0 / dir;
+    var t0 = -origin * inv;
+    var t1 = (vec3<f32>(size) - origin) * inv;
+    var t_near = min(t0, t1);
+    var t_far = max(t0, t1);
+    var t_enter = max(max(t_near.x, t_near.y), t_near.z);
+    var t_exit = min(min(t_far.x, t_far.y), t_far.z);
+    if t_exit < max(t_enter, 0.0) {
+        return vec4(0.0);
+    }
+
+    // Amanatides & Woo DDA, one cell at a time
+    var t = max(t_enter, 0.0);
+    var cell = clamp(vec3<i32>(floor(origin + dir * t)), vec3(0), size - 1);
+    var step = vec3<i32>(sign(dir));
+    var delta = abs(inv);
+    var next = (vec3<f32>(cell) + max(sign(dir), vec3(0.0)) - origin) * inv;
+    var normal = -sign(dir) * vec3<f32>(t_near == vec3(t_enter));
+
+    var color = vec4(0.0);
+    var in_glass = false;
+    var max_steps = size.x + size.y + size.z;
+    for (var i = 0; i < max_steps; i++) {
+        var value = textureLoad(t_voxels, cell, 0).r;
+        if value == 0u {
+            in_glass = false;
+        } else {
+            var index = value - 1u;
+            var coord = vec2(index % edge, index / edge);
+            var translucent = textureLoad(t_color, coord, 0).a < 1.0 || textureLoad(t_material, coord, 0).a > 0.0;
+            // Glass next to glass merges into a single volume, like the rasterized faces
+            if !(translucent && in_glass) {
+                var hit = origin + dir * t;
+                var lit = shade(coord, hit - 0.5, normal, voxel_occlusion(cell, normal, hit));
+                color += (1.0 - color.a) * lit;
+            }
+            in_glass = translucent;
+            if color.a > 0.99 {
+                break;
+            }
+        }
 
-    return ambient_comp + diffuse * diffuse_map + specular * specular_map;
+        if next.x < next.y && next.x < next.z {
+            cell.x += step.x;
+            t = next.x;
+            next.x += delta.x;
+            normal = vec3(-f32(step.x), 0.0, 0.0);
+        } else if next.y < next.z {
+            cell.y += step.y;
+            t = next.y;
+            next.y += delta.y;
+            normal = vec3(0.0, -f32(step.y), 0.0);
+        } else {
+            cell.z += step.z;
+            t = next.z;
+            next.z += delta.z;
+            normal = vec3(0.0, 0.0, -f32(step.z));
+        }
+        if any(cell < vec3(0)) || any(cell >= size) {
+            break;
+        }
+    }
+    // Premultiplied, blended over the clear color
+    return color;
 }
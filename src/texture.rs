@@ -57,13 +57,33 @@ impl Texture {
         dimensions: (u32, u32),
         label: Option<&str>,
     ) -> Self {
+        Self::from_raw(device, queue, rgba, dimensions, wgpu::TextureFormat::Rgba8UnormSrgb, label)
+    }
+
+    /// Like `from_image` but the data is not color (so no sRGB conversion)
+    pub fn from_data(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        rgba: &[u8],
+        dimensions: (u32, u32),
+        label: Option<&str>,
+    ) -> Self {
+        Self::from_raw(device, queue, rgba, dimensions, wgpu::TextureFormat::Rgba8Unorm, label)
+    }
 
+    fn from_raw(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        rgba: &[u8],
+        dimensions: (u32, u32),
+        format: wgpu::TextureFormat,
+        label: Option<&str>,
+    ) -> Self {
         let size = wgpu::Extent3d {
             width: dimensions.0,
             height: dimensions.1,
             depth_or_array_layers: 1,
         };
-        let format = wgpu::TextureFormat::Rgba8UnormSrgb;
         let texture = device.create_texture(&wgpu::TextureDescriptor {
             label,
             size,
@@ -109,6 +129,57 @@ impl Texture {
         }
     }
 
+    /// 3D texture with one palette index per texel, the data is x first, then y, then z
+    pub fn from_voxels(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        indices: &[u32],
+        dimensions: (u32, u32, u32),
+        label: Option<&str>,
+    ) -> Self {
+        let size = wgpu::Extent3d {
+            width: dimensions.0,
+            height: dimensions.1,
+            depth_or_array_layers: dimensions.2,
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label,
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D3,
+            format: wgpu::TextureFormat::R32Uint,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                aspect: wgpu::TextureAspect::All,
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            bytemuck::cast_slice(indices),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(4 * dimensions.0),
+                rows_per_image: Some(dimensions.1),
+            },
+            size,
+        );
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        // Integer textures can't be filtered, only textureLoad is used
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor::default());
+
+        Self {
+            texture,
+            view,
+            sampler,
+        }
+    }
+
     pub fn white(device: &wgpu::Device, queue: &wgpu::Queue) -> Texture {
         Self::from_image(&device, &queue, &[255, 255, 255, 255], (1, 1), Some("white_texture"))
     }
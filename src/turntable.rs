@@ -0,0 +1,173 @@
+use std::{fs, path::Path};
+
+use anyhow::Context;
+use cgmath::{Basis3, Deg, Rotation, Rotation3};
+
+use crate::headless::{HeadlessRenderer, write_png};
+
+/// PNG sides are stored in 31 bits
+const MAX_PNG_SIDE: u32 = i32::MAX as u32;
+
+/// How the frames of a turntable are saved
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Layout {
+    /// One image per frame, numbered after the output name (`out.png` -> `out_000.png`, `out_001.png`, ...)
+    #[default]
+    Sequence,
+    /// Every frame packed in a grid in a single image, with a JSON atlas next to it describing where they are
+    Sheet,
+}
+
+struct Frame {
+    angle: f32,
+    pixels: Vec<u8>,
+}
+
+/// Renders `frames` views orbiting around the vertical axis through the camera target, starting from the current eye.
+/// The light moves with the camera, so every frame is lit from the same side on screen (what sprites usually want).
+pub fn render_turntable(renderer: &mut HeadlessRenderer, frames: u32, layout: Layout, output: &Path) -> anyhow::Result<()> {
+    anyhow::ensure!(frames > 0, "A turntable needs at least one frame");
+
+    let camera = &renderer.app.world_state.camera;
+    let (target, eye, light) = (camera.target, camera.eye - camera.target, camera.light - camera.target);
+    let mut rendered = Vec::with_capacity(frames as usize);
+    for i in 0..frames {
+        let angle = 360.0 * i as f32 / frames as f32;
+        let rotation = Basis3::from_angle_y(Deg(angle));
+        renderer.look_at(target + rotation.rotate_vector(eye), target);
+        renderer.app.world_state.camera.light = target + rotation.rotate_vector(light);
+        rendered.push(Frame { angle, pixels: renderer.render()? });
+    }
+
+    match layout {
+        Layout::Sequence => write_sequence(&rendered, renderer.size(), output),
+        Layout::Sheet => write_sheet(&rendered, renderer.size(), output),
+    }
+}
+
+fn write_sequence(frames: &[Frame], size: (u32, u32), output: &Path) -> anyhow::Result<()> {
+    let stem = output.file_stem().and_then(|x| x.to_str()).context("Invalid output name")?;
+    for (i, frame) in frames.iter().enumerate() {
+        let path = output.with_file_name(format!("{stem}_{i:03}.png"));
+        write_png(&path, size, &frame.pixels)?;
+    }
+    log::info!("Written {} frames as {}", frames.len(), output.with_file_name(format!("{stem}_*.png")).display());
+    Ok(())
+}
+
+/// Frames go left to right, top to bottom, in a grid as square as possible.
+/// The atlas is written in a `.json` file with the same name as the image:
+/// ```json
+/// {"image":"out.png","width":256,"height":256,"frameWidth":128,"frameHeight":128,
+///  "frames":[{"index":0,"angle":0,"x":0,"y":0,"w":128,"h":128}, ...]}
+/// ```
+fn write_sheet(frames: &[Frame], (width, height): (u32, u32), output: &Path) -> anyhow::Result<()> {
+    let count = frames.len() as u32;
+    let columns = (count as f32).sqrt().ceil() as u32;
+    let rows = count.div_ceil(columns);
+    let (sheet_width, sheet_height) = columns.checked_mul(width)
+        .zip(rows.checked_mul(height))
+        .filter(|(w, h)| *w <= MAX_PNG_SIDE && *h <= MAX_PNG_SIDE)
+        .with_context(|| format!("A {columns}x{rows} sheet of {width}x{height} frames is too big for a PNG"))?;
+    let bytes = usize::try_from(sheet_width as u64 * 4 * sheet_height as u64)
+        .context("The sprite sheet doesn't fit in memory")?;
+
+    // Unused cells stay transparent
+    let row = width as usize * 4;
+    let sheet_row = sheet_width as usize * 4;
+    let mut sheet = vec![0; bytes];
+    let mut atlas = Vec::with_capacity(frames.len());
+    for (i, frame) in frames.iter().enumerate() {
+        let (x, y) = (i as u32 % columns * width, i as u32 / columns * height);
+        for (line, pixels) in frame.pixels.chunks_exact(row).enumerate() {
+            let start = (y as usize + line) * sheet_row + x as usize * 4;
+            sheet[start..start + row].copy_from_slice(pixels);
+        }
+        atlas.push(format!(
+            r#"{{"index":{i},"angle":{},"x":{x},"y":{y},"w":{width},"h":{height}}}"#,
+            frame.angle,
+        ));
+    }
+    write_png(output, (sheet_width, sheet_height), &sheet)?;
+
+    let image = output.file_name().and_then(|x| x.to_str()).context("Invalid output name")?;
+    let json = format!(
+        r#"{{"image":"{}","width":{sheet_width},"height":{sheet_height},"frameWidth":{width},"frameHeight":{height},"frames":[{}]}}"#,
+        image.replace('\\', "\\\\").replace('"', "\\\""), atlas.join(","),
+    );
+    let atlas_path = output.with_extension("json");
+    fs::write(&atlas_path, json).context("Cannot write the atlas")?;
+    log::info!("Written a {columns}x{rows} sprite sheet to {}, atlas in {}", output.display(), atlas_path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+
+    fn frame(angle: f32, color: u8) -> Frame {
+        Frame { angle, pixels: [color, color, color, 255].repeat(2) }
+    }
+
+    #[test]
+    fn sheet_and_atlas() {
+        let path = std::env::temp_dir().join(format!("snowoxel-sheet-{}.png", std::process::id()));
+        let frames = [frame(0.0, 10), frame(120.0, 20), frame(240.0, 30)];
+        write_sheet(&frames, (2, 1), &path).unwrap();
+
+        let mut reader = png::Decoder::new(File::open(&path).unwrap()).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        // Three frames in a 2x2 grid, the last cell is empty
+        assert_eq!((info.width, info.height), (4, 2));
+        let pixel = |x: usize, y: usize| pixels[(y * 4 + x) * 4..][..4].to_vec();
+        assert_eq!(pixel(0, 0), [10, 10, 10, 255]);
+        assert_eq!(pixel(3, 0), [20, 20, 20, 255]);
+        assert_eq!(pixel(1, 1), [30, 30, 30, 255]);
+        assert_eq!(pixel(2, 1), [0; 4]);
+
+        let json = fs::read_to_string(path.with_extension("json")).unwrap();
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert_eq!(json, format!(concat!(
+            r#"{{"image":"{}","width":4,"height":2,"frameWidth":2,"frameHeight":1,"frames":["#,
+            r#"{{"index":0,"angle":0,"x":0,"y":0,"w":2,"h":1}},"#,
+            r#"{{"index":1,"angle":120,"x":2,"y":0,"w":2,"h":1}},"#,
+            r#"{{"index":2,"angle":240,"x":0,"y":1,"w":2,"h":1}}]}}"#,
+        ), name));
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(path.with_extension("json")).unwrap();
+    }
+
+    #[test]
+    fn sequence_names() {
+        let dir = std::env::temp_dir().join(format!("snowoxel-sequence-{}", std::process::id()));
+        fs::create_dir(&dir).unwrap();
+        let frames = [frame(0.0, 10), frame(120.0, 20), frame(240.0, 30)];
+        write_sequence(&frames, (2, 1), &dir.join("spin.png")).unwrap();
+
+        let mut names = fs::read_dir(&dir).unwrap().map(|x| x.unwrap().file_name().into_string().unwrap()).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["spin_000.png", "spin_001.png", "spin_002.png"]);
+        for (name, color) in names.iter().zip([10, 20, 30]) {
+            let mut reader = png::Decoder::new(File::open(dir.join(name)).unwrap()).read_info().unwrap();
+            let mut pixels = vec![0; reader.output_buffer_size()];
+            let info = reader.next_frame(&mut pixels).unwrap();
+            assert_eq!((info.width, info.height), (2, 1));
+            assert_eq!(pixels, [color, color, color, 255].repeat(2));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sheet_too_big() {
+        let path = std::env::temp_dir().join(format!("snowoxel-huge-{}.png", std::process::id()));
+        let frames = |count| (0..count).map(|_| Frame { angle: 0.0, pixels: Vec::new() }).collect::<Vec<_>>();
+        // 3 columns of 2^30 pixels overflow a u32, 2 columns are over the PNG limit
+        assert!(write_sheet(&frames(5), (1 << 30, 1), &path).is_err());
+        assert!(write_sheet(&frames(2), (1 << 30, 1), &path).is_err());
+        assert!(write_sheet(&frames(4), (1, 1 << 30), &path).is_err());
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,139 @@
+use std::{fs::File, io::{BufWriter, Write}, path::Path};
+
+use anyhow::Context;
+
+use crate::parser::Scene;
+
+mod vly;
+mod vly_bin;
+mod vox;
+mod ply;
+mod obj;
+mod gltf;
+
+
+/// Output formats, chosen from the extension of the output path
+enum Format {
+    Vly,
+    VlyBin,
+    Vox,
+    Ply,
+    Obj,
+    Gltf,
+}
+
+pub fn write_scene(scene: &Scene, path: &Path) -> anyhow::Result<()> {
+    let ext = path.extension()
+        .and_then(|x| x.to_str())
+        .context("Cannot determine output format")?;
+    // Before creating the file, an unsupported format must not truncate it
+    let format = match ext {
+        "vly" => Format::Vly,
+        "vlyb" => Format::VlyBin,
+        "vox" => Format::Vox,
+        "ply" => Format::Ply,
+        "obj" => Format::Obj,
+        "glb" => Format::Gltf,
+        _ => anyhow::bail!("Unsupported output format: {ext}"),
+    };
+
+    let file = File::create(path).context("Cannot create output file")?;
+    let mut out = BufWriter::new(file);
+
+    match format {
+        Format::Vly => vly::write_scene(scene, &mut out)?,
+        Format::VlyBin => vly_bin::write_scene(scene, &mut out)?,
+        Format::Vox => vox::write_scene(scene, &mut out)?,
+        Format::Ply => ply::write_scene(scene, &mut out)?,
+        Format::Obj => {
+            // Materials go in a file next to the model
+            let mtl_path = path.with_extension("mtl");
+            let mtl_name = mtl_path.file_name().and_then(|x| x.to_str()).context("Invalid output name")?;
+            let mut mtl = BufWriter::new(File::create(&mtl_path).context("Cannot create material file")?);
+            obj::write_scene(scene, &mut out, mtl_name, &mut mtl)?;
+            mtl.flush()?;
+        },
+        Format::Gltf => gltf::write_scene(scene, &mut out)?,
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, fs, io, path::PathBuf};
+
+    use crate::{color::Color, parser::{parse_scene, vly_scene}};
+
+    use super::*;
+
+    /// Writes `scene` with `write` and parses the result back, `file_name` picks the format
+    pub(super) fn write_and_parse(scene: &Scene, file_name: &str, write: impl Fn(&Scene, &mut Vec<u8>) -> io::Result<()>) -> Scene {
+        let mut data = Vec::new();
+        write(scene, &mut data).unwrap();
+        parse_scene(&data, Some(OsStr::new(file_name)), &Default::default()).unwrap()
+    }
+
+    /// Every `.vly` file in `models/` through `write_and_parse`: (path, original scene, parsed output)
+    pub(super) fn round_trip_models(file_name: &str, write: impl Fn(&Scene, &mut Vec<u8>) -> io::Result<()>) -> Vec<(PathBuf, Scene, Scene)> {
+        let mut models: Vec<_> = fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/models"))
+            .unwrap()
+            .map(|x| x.unwrap().path())
+            .filter(|x| x.extension() == Some(OsStr::new("vly")))
+            .collect();
+        models.sort();
+        assert!(!models.is_empty());
+        models.into_iter().map(|path| {
+            let scene = parse_scene(&fs::read(&path).unwrap(), path.file_name(), &Default::default()).unwrap();
+            let parsed = write_and_parse(&scene, file_name, &write);
+            (path, scene, parsed)
+        }).collect()
+    }
+
+    /// Position and actual color of every voxel, palette indices and order can change between formats
+    fn voxel_colors(scene: &Scene) -> Vec<([u32; 3], [u8; 4])> {
+        let mut voxels: Vec<_> = scene.voxels.iter()
+            .map(|x| {
+                let Color { r, g, b, a } = scene.colors[x.color as usize];
+                ([x.pos.x, x.pos.y, x.pos.z], [r, g, b, a])
+            })
+            .collect();
+        voxels.sort_by_key(|(pos, _color)| *pos);
+        voxels
+    }
+
+    #[test]
+    fn vox_round_trip() {
+        for (path, scene, parsed) in round_trip_models("out.vox", vox::write_scene) {
+            assert_eq!(parsed.grid_size, scene.grid_size, "{}", path.display());
+            assert_eq!(voxel_colors(&parsed), voxel_colors(&scene), "{} changed", path.display());
+        }
+    }
+
+    #[test]
+    fn vox_splits_big_scenes() {
+        // 300 along x and 600 along z in vly coordinates, more than one 256³ model on both axes
+        let voxels = [[2, 1, 3, 0], [299, 1, 3, 1], [2, 5, 599, 1], [150, 3, 300, 0], [299, 5, 599, 0]];
+        let text: String = voxels.iter().map(|[x, y, z, c]| format!("{x} {y} {z} {c}\n")).collect();
+        let scene = vly_scene(&format!("grid_size: 300 6 600\nvoxel_num: {}\n{text}0 255 0 0\n1 0 0 255\n", voxels.len()));
+        let parsed = write_and_parse(&scene, "out.vox", vox::write_scene);
+
+        // The reader puts the smallest position of a scene graph at the origin
+        let shift = scene.voxels.iter().fold([u32::MAX; 3], |a, v| [a[0].min(v.pos.x), a[1].min(v.pos.y), a[2].min(v.pos.z)]);
+        assert_eq!(shift, [2, 3, 1]);
+        let expected: Vec<_> = voxel_colors(&scene).into_iter()
+            .map(|([x, y, z], color)| ([x - shift[0], y - shift[1], z - shift[2]], color))
+            .collect();
+        assert_eq!(voxel_colors(&parsed), expected);
+    }
+
+    #[test]
+    fn unsupported_format_keeps_the_file() {
+        let path = std::env::temp_dir().join(format!("snowoxel-{}.xyz", std::process::id()));
+        fs::write(&path, b"precious").unwrap();
+        let scene = vly_scene("grid_size: 1 1 1\nvoxel_num: 1\n0 0 0 0\n0 255 0 0\n");
+        assert!(write_scene(&scene, &path).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"precious");
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,228 @@
+use std::{collections::BTreeMap, io::{self, Write}};
+
+use crate::{parser::Scene, material::Material};
+
+use crate::mesh::{visible_faces, QUAD_TRIANGLES};
+
+// Binary glTF: https://registry.khronos.org/glTF/specs/2.0/glTF-2.0.html#binary-gltf-layout
+const MAGIC_BYTES: &[u8; 4] = b"glTF";
+const VERSION: u32 = 2;
+const JSON_CHUNK: &[u8; 4] = b"JSON";
+const BIN_CHUNK: &[u8; 4] = b"BIN\0";
+
+const FLOAT: u32 = 5126;
+const UNSIGNED_INT: u32 = 5125;
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+
+/// Faces that share the same glTF material end up in the same primitive
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum MaterialKey {
+    /// Only the vertex color changes
+    Plain { translucent: bool },
+    /// Palette entry with its own material
+    Special(u32),
+}
+
+/// glTF vertex colors are linear
+fn srgb_to_linear(x: u8) -> f32 {
+    let x = x as f32 / 255.0;
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn push_floats(out: &mut Vec<u8>, values: &[f32]) {
+    for x in values {
+        out.extend_from_slice(&x.to_le_bytes());
+    }
+}
+
+fn material_json(scene: &Scene, key: MaterialKey) -> String {
+    let default = Material::default();
+    let (material, translucent, name) = match key {
+        MaterialKey::Plain { translucent } => (&default, translucent, if translucent { "translucent" } else { "opaque" }.to_string()),
+        MaterialKey::Special(color) => (
+            scene.materials.get(color as usize).unwrap_or(&default),
+            scene.is_translucent(color),
+            format!("color_{color}"),
+        ),
+    };
+
+    let mut json = format!(
+        r#"{{"name":"{name}","pbrMetallicRoughness":{{"baseColorFactor":[1,1,1,{}],"metallicFactor":{},"roughnessFactor":{}}}"#,
+        1.0 - material.transparency, material.metalness, material.roughness,
+    );
+    // Only palette entries with their own material can glow
+    if let (MaterialKey::Special(color), true) = (key, material.emission > 0.0) {
+        let color = scene.colors.get(color as usize).copied().unwrap_or_default();
+        let [r, g, b] = [color.r, color.g, color.b].map(srgb_to_linear);
+        json += &format!(r#","emissiveFactor":[{r},{g},{b}]"#);
+        if material.emission > 1.0 {
+            json += &format!(r#","extensions":{{"KHR_materials_emissive_strength":{{"emissiveStrength":{}}}}}"#, material.emission);
+        }
+    }
+    if translucent {
+        json += r#","alphaMode":"BLEND""#;
+    }
+    json += "}";
+    json
+}
+
+/// Writes the visible faces as a binary glTF (.glb) with vertex colors,
+/// palette entries with a material get a glTF material of their own
+pub fn write_scene(scene: &Scene, out: &mut impl Write) -> io::Result<()> {
+    let faces = visible_faces(scene);
+    if faces.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Nothing to export, the scene is empty"));
+    }
+    let vertex_count = faces.len() * 4;
+
+    let mut positions = Vec::with_capacity(vertex_count * 12);
+    let mut normals = Vec::with_capacity(vertex_count * 12);
+    let mut colors = Vec::with_capacity(vertex_count * 16);
+    let mut min = [f32::INFINITY; 3];
+    let mut max = [f32::NEG_INFINITY; 3];
+    let mut primitives: BTreeMap<MaterialKey, Vec<u32>> = BTreeMap::new();
+    for (i, face) in faces.iter().enumerate() {
+        let color = scene.colors.get(face.color as usize).copied().unwrap_or_default();
+        let linear = [color.r, color.g, color.b].map(srgb_to_linear);
+        for corner in face.corners {
+            let corner = corner.map(|x| x as f32);
+            for axis in 0..3 {
+                min[axis] = min[axis].min(corner[axis]);
+                max[axis] = max[axis].max(corner[axis]);
+            }
+            push_floats(&mut positions, &corner);
+            push_floats(&mut normals, &face.normal());
+            push_floats(&mut colors, &[linear[0], linear[1], linear[2], color.a as f32 / 255.0]);
+        }
+
+        let special = scene.materials.get(face.color as usize).map_or(false, |x| *x != Material::default());
+        let key = if special {
+            MaterialKey::Special(face.color)
+        } else {
+            MaterialKey::Plain { translucent: scene.is_translucent(face.color) }
+        };
+        let base = i as u32 * 4;
+        primitives.entry(key).or_default().extend(QUAD_TRIANGLES.map(|x| base + x));
+    }
+
+    // Buffer views: positions, normals, colors and then the indices of every primitive
+    let mut bin = Vec::new();
+    let mut views = Vec::new();
+    let mut accessors = vec![
+        format!(
+            r#"{{"bufferView":0,"componentType":{FLOAT},"count":{vertex_count},"type":"VEC3","min":[{},{},{}],"max":[{},{},{}]}}"#,
+            min[0], min[1], min[2], max[0], max[1], max[2],
+        ),
+        format!(r#"{{"bufferView":1,"componentType":{FLOAT},"count":{vertex_count},"type":"VEC3"}}"#),
+        format!(r#"{{"bufferView":2,"componentType":{FLOAT},"count":{vertex_count},"type":"VEC4"}}"#),
+    ];
+    for data in [&positions, &normals, &colors] {
+        views.push(format!(r#"{{"buffer":0,"byteOffset":{},"byteLength":{},"target":{ARRAY_BUFFER}}}"#, bin.len(), data.len()));
+        bin.extend_from_slice(data);
+    }
+
+    let mut materials = Vec::new();
+    let mut primitive_json = Vec::new();
+    for (key, indices) in primitives.iter() {
+        let view = views.len();
+        let accessor = accessors.len();
+        views.push(format!(
+            r#"{{"buffer":0,"byteOffset":{},"byteLength":{},"target":{ELEMENT_ARRAY_BUFFER}}}"#,
+            bin.len(), indices.len() * 4,
+        ));
+        for x in indices {
+            bin.extend_from_slice(&x.to_le_bytes());
+        }
+        accessors.push(format!(r#"{{"bufferView":{view},"componentType":{UNSIGNED_INT},"count":{},"type":"SCALAR"}}"#, indices.len()));
+        primitive_json.push(format!(
+            r#"{{"attributes":{{"POSITION":0,"NORMAL":1,"COLOR_0":2}},"indices":{accessor},"material":{}}}"#,
+            materials.len(),
+        ));
+        materials.push(material_json(scene, *key));
+    }
+
+    let extensions = if materials.iter().any(|x| x.contains("KHR_materials_emissive_strength")) {
+        r#""extensionsUsed":["KHR_materials_emissive_strength"],"#
+    } else {
+        ""
+    };
+    let json = format!(
+        concat!(
+            r#"{{"asset":{{"version":"2.0","generator":"voxel renderer"}},{}"scene":0,"scenes":[{{"nodes":[0]}}],"#,
+            r#""nodes":[{{"mesh":0,"name":"voxels"}}],"meshes":[{{"primitives":[{}]}}],"materials":[{}],"#,
+            r#""accessors":[{}],"bufferViews":[{}],"buffers":[{{"byteLength":{}}}]}}"#,
+        ),
+        extensions, primitive_json.join(","), materials.join(","),
+        accessors.join(","), views.join(","), bin.len(),
+    );
+
+    // Chunks are aligned to 4 bytes, JSON is padded with spaces and the binary data with zeros
+    let mut json = json.into_bytes();
+    while json.len() % 4 != 0 {
+        json.push(b' ');
+    }
+    while bin.len() % 4 != 0 {
+        bin.push(0);
+    }
+
+    let total = 12 + 8 + json.len() + 8 + bin.len();
+    out.write_all(MAGIC_BYTES)?;
+    out.write_all(&VERSION.to_le_bytes())?;
+    out.write_all(&(total as u32).to_le_bytes())?;
+    out.write_all(&(json.len() as u32).to_le_bytes())?;
+    out.write_all(JSON_CHUNK)?;
+    out.write_all(&json)?;
+    out.write_all(&(bin.len() as u32).to_le_bytes())?;
+    out.write_all(BIN_CHUNK)?;
+    out.write_all(&bin)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::vly_scene;
+
+    use super::*;
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn glb_layout() {
+        let scene = vly_scene("grid_size: 2 1 1\nvoxel_num: 2\n0 0 0 0\n1 0 0 1\n0 255 0 0\n1 0 0 255\n");
+        let mut data = Vec::new();
+        write_scene(&scene, &mut data).unwrap();
+
+        assert_eq!(&data[..4], MAGIC_BYTES);
+        assert_eq!(u32_at(&data, 4), VERSION);
+        assert_eq!(u32_at(&data, 8) as usize, data.len());
+
+        let json_len = u32_at(&data, 12) as usize;
+        assert_eq!(&data[16..20], JSON_CHUNK);
+        assert_eq!(json_len % 4, 0);
+        let json = std::str::from_utf8(&data[20..20 + json_len]).unwrap();
+
+        let bin_start = 20 + json_len;
+        let bin_len = u32_at(&data, bin_start) as usize;
+        assert_eq!(&data[bin_start + 4..bin_start + 8], BIN_CHUNK);
+        assert_eq!(bin_start + 8 + bin_len, data.len());
+        assert!(json.contains(&format!(r#""buffers":[{{"byteLength":{bin_len}}}]"#)));
+
+        // 10 quads: 40 vertices, 60 indices in one opaque primitive
+        assert!(json.contains(r#""count":40,"type":"VEC3","min":[0,0,0],"max":[2,1,1]"#));
+        assert!(json.contains(r#""count":60,"type":"SCALAR""#));
+        assert_eq!(bin_len, 40 * (12 + 12 + 16) + 60 * 4);
+    }
+
+    #[test]
+    fn empty_scene() {
+        let scene = vly_scene("grid_size: 1 1 1\nvoxel_num: 0\n");
+        assert!(write_scene(&scene, &mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use std::{collections::{HashMap, BTreeSet}, io::{self, Write}};
+
+use crate::{parser::Scene, material::{Material, MaterialKind}};
+
+use crate::mesh::{visible_faces, NORMALS};
+
+
+fn material_name(color: u32) -> String {
+    format!("color_{color}")
+}
+
+/// Writes the visible faces as a Wavefront OBJ mesh, every palette color becomes a material in `mtl`
+pub fn write_scene(scene: &Scene, out: &mut impl Write, mtl_name: &str, mtl: &mut impl Write) -> io::Result<()> {
+    let mut faces = visible_faces(scene);
+    // usemtl applies to every face after it, keep the ones with the same color together
+    faces.sort_by_key(|x| x.color);
+
+    writeln!(out, "# voxel scene, one unit per voxel, y up")?;
+    writeln!(out, "mtllib {mtl_name}")?;
+
+    // Corners are shared between faces, they only need to be written once
+    let mut vertices = HashMap::new();
+    let mut face_indices = Vec::with_capacity(faces.len());
+    for face in faces.iter() {
+        let corners = face.corners.map(|corner| {
+            let next = vertices.len() + 1;
+            *vertices.entry(corner).or_insert(next)
+        });
+        face_indices.push(corners);
+    }
+    let mut ordered = vec![[0; 3]; vertices.len()];
+    for (corner, index) in vertices {
+        ordered[index - 1] = corner;
+    }
+    for [x, y, z] in ordered {
+        writeln!(out, "v {x} {y} {z}")?;
+    }
+    for [x, y, z] in NORMALS {
+        writeln!(out, "vn {x} {y} {z}")?;
+    }
+
+    let mut current = None;
+    for (face, [a, b, c, d]) in faces.iter().zip(face_indices) {
+        if current != Some(face.color) {
+            writeln!(out, "usemtl {}", material_name(face.color))?;
+            current = Some(face.color);
+        }
+        let n = face.direction + 1;
+        writeln!(out, "f {a}//{n} {b}//{n} {c}//{n} {d}//{n}")?;
+    }
+
+    let used: BTreeSet<u32> = faces.iter().map(|x| x.color).collect();
+    write_materials(scene, &used, mtl)
+}
+
+fn write_materials(scene: &Scene, used: &BTreeSet<u32>, out: &mut impl Write) -> io::Result<()> {
+    let default = Material::default();
+    for color_index in used {
+        let color = scene.colors.get(*color_index as usize).copied().unwrap_or_default();
+        let material = scene.materials.get(*color_index as usize).unwrap_or(&default);
+        let [r, g, b] = [color.r, color.g, color.b].map(|x| x as f32 / 255.0);
+
+        writeln!(out, "newmtl {}", material_name(*color_index))?;
+        writeln!(out, "Kd {r:.4} {g:.4} {b:.4}")?;
+        writeln!(out, "d {:.4}", color.a as f32 / 255.0 * (1.0 - material.transparency))?;
+        // PBR extension, ignored by older importers
+        writeln!(out, "Pr {:.4}", material.roughness)?;
+        writeln!(out, "Pm {:.4}", material.metalness)?;
+        if material.emission > 0.0 {
+            let e = material.emission;
+            writeln!(out, "Ke {:.4} {:.4} {:.4}", r * e, g * e, b * e)?;
+        }
+        if material.kind == MaterialKind::Glass {
+            writeln!(out, "Ni {:.4}", material.ior)?;
+        }
+        writeln!(out, "illum 2")?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::vly_scene;
+
+    use super::*;
+
+    fn lines_starting<'a>(text: &'a str, prefix: &str) -> Vec<&'a str> {
+        text.lines().filter(|x| x.starts_with(prefix)).collect()
+    }
+
+    #[test]
+    fn faces_and_materials() {
+        let scene = vly_scene("grid_size: 2 1 1\nvoxel_num: 2\n0 0 0 0\n1 0 0 1\n0 255 0 0\n1 0 0 255\n");
+        let (mut obj, mut mtl) = (Vec::new(), Vec::new());
+        write_scene(&scene, &mut obj, "out.mtl", &mut mtl).unwrap();
+        let (obj, mtl) = (String::from_utf8(obj).unwrap(), String::from_utf8(mtl).unwrap());
+
+        assert_eq!(lines_starting(&obj, "mtllib "), ["mtllib out.mtl"]);
+        // The corners of two cubes side by side, shared between faces
+        assert_eq!(lines_starting(&obj, "v ").len(), 12);
+        assert_eq!(lines_starting(&obj, "vn ").len(), 6);
+        let faces = lines_starting(&obj, "f ");
+        assert_eq!(faces.len(), 10);
+        for face in faces {
+            for corner in face.split(' ').skip(1) {
+                let (v, n) = corner.split_once("//").unwrap();
+                assert!((1..=12).contains(&v.parse::<u32>().unwrap()));
+                assert!((1..=6).contains(&n.parse::<u32>().unwrap()));
+            }
+        }
+
+        // Every material used by the mesh is defined
+        assert_eq!(lines_starting(&obj, "usemtl "), ["usemtl color_0", "usemtl color_1"]);
+        assert_eq!(lines_starting(&mtl, "newmtl "), ["newmtl color_0", "newmtl color_1"]);
+        assert_eq!(lines_starting(&mtl, "Kd "), ["Kd 1.0000 0.0000 0.0000", "Kd 0.0000 0.0000 1.0000"]);
+    }
+}
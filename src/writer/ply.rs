@@ -0,0 +1,69 @@
+use std::io::{self, Write};
+
+use crate::parser::Scene;
+
+use crate::mesh::visible_faces;
+
+
+/// Writes the visible faces as an ascii PLY mesh, every face has its own vertices with the voxel color
+pub fn write_scene(scene: &Scene, out: &mut impl Write) -> io::Result<()> {
+    let faces = visible_faces(scene);
+
+    if !scene.materials.is_empty() {
+        log::warn!("PLY meshes have no materials, only colors will be kept");
+    }
+
+    writeln!(out, "ply")?;
+    writeln!(out, "format ascii 1.0")?;
+    writeln!(out, "comment voxel scene, one unit per voxel, y up")?;
+    writeln!(out, "element vertex {}", faces.len() * 4)?;
+    for property in ["x", "y", "z", "nx", "ny", "nz"] {
+        writeln!(out, "property float {property}")?;
+    }
+    for property in ["red", "green", "blue", "alpha"] {
+        writeln!(out, "property uchar {property}")?;
+    }
+    writeln!(out, "element face {}", faces.len())?;
+    writeln!(out, "property list uchar uint vertex_indices")?;
+    writeln!(out, "end_header")?;
+
+    for face in faces.iter() {
+        let [nx, ny, nz] = face.normal();
+        let color = scene.colors.get(face.color as usize).copied().unwrap_or_default();
+        for [x, y, z] in face.corners {
+            writeln!(out, "{x} {y} {z} {nx} {ny} {nz} {} {} {} {}", color.r, color.g, color.b, color.a)?;
+        }
+    }
+    for i in 0..faces.len() as u32 {
+        let base = i * 4;
+        writeln!(out, "4 {} {} {} {}", base, base + 1, base + 2, base + 3)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::parser::{parse_model, vly_scene};
+
+    use super::*;
+
+    #[test]
+    fn faces_and_colors() {
+        let scene = vly_scene("grid_size: 2 1 1\nvoxel_num: 2\n0 0 0 0\n1 0 0 1\n0 255 0 0\n1 0 0 255\n");
+        let mut data = Vec::new();
+        write_scene(&scene, &mut data).unwrap();
+        let text = String::from_utf8(data.clone()).unwrap();
+        assert!(text.contains("element vertex 40\n"));
+        assert!(text.contains("element face 10\n"));
+
+        // Read back by the importer, quads are split in two triangles
+        let model = parse_model(Cursor::new(data)).unwrap();
+        assert_eq!(model.vertices.len(), 40);
+        assert_eq!(model.indices.len(), 10 * 6);
+        let colors = model.colors.unwrap();
+        assert_eq!(colors.iter().filter(|c| c.r == 255).count(), 20);
+        assert_eq!(colors.iter().filter(|c| c.b == 255).count(), 20);
+    }
+}
@@ -0,0 +1,38 @@
+use std::io::{self, Write};
+
+use crate::parser::Scene;
+
+
+/// Writes the scene in the same layout read by the parser,
+/// y and z are swapped back to the file convention
+pub fn write_scene(scene: &Scene, out: &mut impl Write) -> io::Result<()> {
+    let size = scene.grid_size;
+    writeln!(out, "grid_size: {} {} {}", size.x, size.z, size.y)?;
+    writeln!(out, "voxel_num: {}", scene.voxels.len())?;
+
+    for voxel in scene.voxels.iter() {
+        writeln!(out, "{} {} {} {}", voxel.pos.x, voxel.pos.z, voxel.pos.y, voxel.color)?;
+    }
+
+    if scene.colors.iter().any(|x| !x.is_opaque()) || !scene.materials.is_empty() {
+        log::warn!("The vly format has no transparency nor materials, they will be lost");
+    }
+    for (i, color) in scene.colors.iter().enumerate() {
+        writeln!(out, "{} {} {} {}", i, color.r, color.g, color.b)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::writer::tests::round_trip_models;
+
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for (path, scene, parsed) in round_trip_models("out.vly", write_scene) {
+            assert!(parsed == scene, "{} changed", path.display());
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use std::io::{self, Write};
+
+use cgmath::Vector3;
+
+use crate::parser::{Scene, vly_bin::{MAGIC_BYTES, VERSION}};
+
+
+fn push_varint(out: &mut Vec<u8>, mut x: u64) {
+    loop {
+        let byte = (x & 0x7f) as u8;
+        x >>= 7;
+        if x == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn push_delta(out: &mut Vec<u8>, x: i64) {
+    // zigzag encoding, small negative deltas stay small
+    push_varint(out, ((x << 1) ^ (x >> 63)) as u64);
+}
+
+pub fn write_scene(scene: &Scene, out: &mut impl Write) -> io::Result<()> {
+    let mut data = Vec::with_capacity(32 + scene.colors.len() * 4 + scene.voxels.len() * 4);
+    data.extend_from_slice(MAGIC_BYTES);
+    data.push(VERSION);
+    for x in [scene.grid_size.x, scene.grid_size.y, scene.grid_size.z] {
+        push_varint(&mut data, x as u64);
+    }
+    push_varint(&mut data, scene.colors.len() as u64);
+    push_varint(&mut data, scene.voxels.len() as u64);
+
+    for color in scene.colors.iter() {
+        data.extend_from_slice(&[color.r, color.g, color.b, color.a]);
+    }
+
+    if !scene.materials.is_empty() {
+        log::warn!("The binary vly format has no materials, they will be lost");
+    }
+    let mut last = Vector3::new(0i64, 0, 0);
+    for voxel in scene.voxels.iter() {
+        let pos = voxel.pos.cast::<i64>().unwrap();
+        let delta = pos - last;
+        push_delta(&mut data, delta.x);
+        push_delta(&mut data, delta.y);
+        push_delta(&mut data, delta.z);
+        push_varint(&mut data, voxel.color as u64);
+        last = pos;
+    }
+
+    out.write_all(&data)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{parser::parse_scene, writer::tests::{round_trip_models, write_and_parse}};
+
+    use super::*;
+
+    #[test]
+    fn same_scene_as_vly() {
+        for (path, scene, parsed) in round_trip_models("out.vlyb", write_scene) {
+            assert!(parsed == scene, "{} changed", path.display());
+        }
+    }
+
+    #[test]
+    fn extreme_positions() {
+        // Jumps from one end of the grid to the other, in both directions
+        let vly = "grid_size: 4294967295 4294967295 1\nvoxel_num: 3\n0 0 0 0\n4294967294 4294967294 0 1\n0 1 0 0\n0 1 2 3\n1 4 5 6\n";
+        let scene = parse_scene(vly.as_bytes(), None, &Default::default()).unwrap();
+        assert_eq!(scene.voxels.len(), 3);
+        assert!(write_and_parse(&scene, "out.vlyb", write_scene) == scene);
+    }
+}
@@ -0,0 +1,215 @@
+use std::{collections::BTreeMap, io::{self, Write}};
+
+use cgmath::Vector3;
+
+use crate::{color::Color, material::{Material, MaterialKind}, parser::Scene};
+
+// MagicaVoxel can't open models bigger than this
+const MAX_MODEL_SIZE: u32 = 256;
+const VERSION: u32 = 150;
+
+
+fn push_int4(out: &mut Vec<u8>, x: u32) {
+    out.extend_from_slice(&x.to_le_bytes());
+}
+
+fn push_string(out: &mut Vec<u8>, s: &str) {
+    push_int4(out, s.len() as u32);
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn push_dict(out: &mut Vec<u8>, dict: &[(&str, String)]) {
+    push_int4(out, dict.len() as u32);
+    for (key, value) in dict {
+        push_string(out, key);
+        push_string(out, value);
+    }
+}
+
+fn push_chunk(out: &mut Vec<u8>, id: &[u8; 4], content: &[u8], children: &[u8]) {
+    out.extend_from_slice(id);
+    push_int4(out, content.len() as u32);
+    push_int4(out, children.len() as u32);
+    out.extend_from_slice(content);
+    out.extend_from_slice(children);
+}
+
+/// Maps scene color indices to .vox ones (1..=255, 0 means empty).
+/// Indices are kept untouched when possible so that .vox files survive a round-trip.
+fn palette_mapping(scene: &Scene) -> io::Result<BTreeMap<u32, u8>> {
+    let mut used: Vec<u32> = scene.voxels.iter().map(|x| x.color).collect();
+    used.sort_unstable();
+    used.dedup();
+
+    let max_used = used.last().copied().unwrap_or(0);
+    if used.first() != Some(&0) && max_used <= 255 {
+        Ok(used.into_iter().map(|x| (x, x as u8)).collect())
+    } else if max_used < 255 {
+        // Index 0 is reserved, shift everything by one
+        Ok(used.into_iter().map(|x| (x, x as u8 + 1)).collect())
+    } else if used.len() <= 255 {
+        Ok(used.into_iter().enumerate().map(|(i, x)| (x, i as u8 + 1)).collect())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Too many colors ({}), .vox supports at most 255", used.len()),
+        ))
+    }
+}
+
+fn material_dict(material: &Material) -> Vec<(&'static str, String)> {
+    let kind = match material.kind {
+        MaterialKind::Diffuse if material.metalness > 0.0 || material.transparency > 0.0 || material.emission > 0.0 => "_blend",
+        MaterialKind::Diffuse => "_diffuse",
+        MaterialKind::Metal => "_metal",
+        MaterialKind::Glass => "_glass",
+        MaterialKind::Emissive => "_emit",
+    };
+    // The parser computes the emission as emit * (1 + flux)
+    let (emit, flux) = if material.emission > 1.0 {
+        (1.0, material.emission - 1.0)
+    } else {
+        (material.emission, 0.0)
+    };
+    let mut dict = vec![
+        ("_type", kind.to_string()),
+        ("_rough", material.roughness.to_string()),
+        ("_ior", (material.ior - 1.0).to_string()),
+    ];
+    match material.kind {
+        MaterialKind::Metal => dict.push(("_metal", material.metalness.to_string())),
+        MaterialKind::Glass => dict.push(("_trans", material.transparency.to_string())),
+        MaterialKind::Emissive => {
+            dict.push(("_emit", emit.to_string()));
+            dict.push(("_flux", flux.to_string()));
+        },
+        MaterialKind::Diffuse => {
+            dict.push(("_metal", material.metalness.to_string()));
+            dict.push(("_trans", material.transparency.to_string()));
+            dict.push(("_emit", material.emission.to_string()));
+        },
+    }
+    dict
+}
+
+pub fn write_scene(scene: &Scene, out: &mut impl Write) -> io::Result<()> {
+    let mapping = palette_mapping(scene)?;
+
+    // Split the scene in blocks that MagicaVoxel can handle,
+    // positions are converted to .vox coordinates (z is up)
+    let mut blocks: BTreeMap<(u32, u32, u32), Vec<[u8; 4]>> = BTreeMap::new();
+    for voxel in scene.voxels.iter() {
+        let pos = Vector3::new(voxel.pos.z, voxel.pos.x, voxel.pos.y);
+        let block = pos / MAX_MODEL_SIZE;
+        let local = pos - block * MAX_MODEL_SIZE;
+        blocks.entry((block.x, block.y, block.z)).or_default()
+            .push([local.x as u8, local.y as u8, local.z as u8, mapping[&voxel.color]]);
+    }
+    let grid_size = Vector3::new(scene.grid_size.z, scene.grid_size.x, scene.grid_size.y);
+
+    let mut children = Vec::new();
+    let mut placements = Vec::new();
+    for (&(bx, by, bz), voxels) in blocks.iter() {
+        let origin = Vector3::new(bx, by, bz) * MAX_MODEL_SIZE;
+        let max = voxels.iter().fold(Vector3::new(0, 0, 0), |a, v| {
+            Vector3::new(a.x.max(v[0] as u32 + 1), a.y.max(v[1] as u32 + 1), a.z.max(v[2] as u32 + 1))
+        });
+        let clip = |size: u32, origin: u32| size.saturating_sub(origin).min(MAX_MODEL_SIZE);
+        let size = Vector3::new(
+            max.x.max(clip(grid_size.x, origin.x)),
+            max.y.max(clip(grid_size.y, origin.y)),
+            max.z.max(clip(grid_size.z, origin.z)),
+        );
+
+        let mut content = Vec::with_capacity(12);
+        push_int4(&mut content, size.x);
+        push_int4(&mut content, size.y);
+        push_int4(&mut content, size.z);
+        push_chunk(&mut children, b"SIZE", &content, &[]);
+
+        let mut content = Vec::with_capacity(4 + voxels.len() * 4);
+        push_int4(&mut content, voxels.len() as u32);
+        content.extend(voxels.iter().flatten());
+        push_chunk(&mut children, b"XYZI", &content, &[]);
+
+        // Models are centered on their translation
+        placements.push(origin + size / 2);
+    }
+
+    // A single model is stored as-is, more of them need a scene graph to be placed
+    // nTRN 0 -> nGRP 1 -> (nTRN 2 -> nSHP 3), (nTRN 4 -> nSHP 5), ...
+    if placements.len() > 1 {
+        let mut content = Vec::new();
+        push_int4(&mut content, 0);
+        push_dict(&mut content, &[]);
+        push_int4(&mut content, 1);
+        push_int4(&mut content, u32::MAX);
+        push_int4(&mut content, u32::MAX);
+        push_int4(&mut content, 1);
+        push_dict(&mut content, &[]);
+        push_chunk(&mut children, b"nTRN", &content, &[]);
+
+        let mut content = Vec::new();
+        push_int4(&mut content, 1);
+        push_dict(&mut content, &[]);
+        push_int4(&mut content, placements.len() as u32);
+        for i in 0..placements.len() as u32 {
+            push_int4(&mut content, 2 + i * 2);
+        }
+        push_chunk(&mut children, b"nGRP", &content, &[]);
+
+        for (i, t) in placements.iter().enumerate() {
+            let id = 2 + i as u32 * 2;
+            let mut content = Vec::new();
+            push_int4(&mut content, id);
+            push_dict(&mut content, &[]);
+            push_int4(&mut content, id + 1);
+            push_int4(&mut content, u32::MAX);
+            push_int4(&mut content, 0);
+            push_int4(&mut content, 1);
+            push_dict(&mut content, &[("_t", format!("{} {} {}", t.x, t.y, t.z))]);
+            push_chunk(&mut children, b"nTRN", &content, &[]);
+
+            let mut content = Vec::new();
+            push_int4(&mut content, id + 1);
+            push_dict(&mut content, &[]);
+            push_int4(&mut content, 1);
+            push_int4(&mut content, i as u32);
+            push_dict(&mut content, &[]);
+            push_chunk(&mut children, b"nSHP", &content, &[]);
+        }
+    }
+
+    // The palette chunk stores the color of index i + 1 at position i
+    let mut palette = vec![Color::new(0, 0, 0); 256];
+    for (&from, &to) in mapping.iter() {
+        if let Some(color) = scene.colors.get(from as usize) {
+            palette[to as usize] = *color;
+        }
+    }
+    let content: Vec<u8> = palette[1..].iter()
+        .chain(std::iter::once(&Color::with_alpha(0, 0, 0, 0)))
+        .flat_map(|c| [c.r, c.g, c.b, c.a])
+        .collect();
+    push_chunk(&mut children, b"RGBA", &content, &[]);
+
+    let default_material = Material::default();
+    for (&from, &to) in mapping.iter() {
+        let material = match scene.materials.get(from as usize) {
+            Some(x) if *x != default_material => x,
+            _ => continue,
+        };
+        let mut content = Vec::new();
+        push_int4(&mut content, to as u32);
+        push_dict(&mut content, &material_dict(material));
+        push_chunk(&mut children, b"MATL", &content, &[]);
+    }
+
+    let mut main = Vec::with_capacity(children.len() + 12);
+    push_chunk(&mut main, b"MAIN", &[], &children);
+
+    out.write_all(b"VOX ")?;
+    out.write_all(&VERSION.to_le_bytes())?;
+    out.write_all(&main)?;
+    Ok(())
+}